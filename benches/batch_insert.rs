@@ -0,0 +1,118 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use pmtree::*;
+use std::collections::HashMap;
+use tiny_keccak::{Hasher as _, Keccak};
+
+struct MemoryDB(HashMap<DBKey, Value>);
+
+#[derive(Default)]
+struct MyKeccak;
+
+#[derive(Default)]
+struct MemoryDBConfig;
+
+impl Database for MemoryDB {
+    type Config = MemoryDBConfig;
+
+    fn new(_db_config: MemoryDBConfig) -> PmtreeResult<Self> {
+        Ok(MemoryDB(HashMap::new()))
+    }
+
+    fn load(_db_config: MemoryDBConfig) -> PmtreeResult<Self> {
+        Err(PmtreeErrorKind::DatabaseError(
+            DatabaseErrorKind::CannotLoadDatabase,
+        ))
+    }
+
+    fn get(&self, key: DBKey) -> PmtreeResult<Option<Value>> {
+        Ok(self.0.get(&key).cloned())
+    }
+
+    fn put(&mut self, key: DBKey, value: Value) -> PmtreeResult<()> {
+        self.0.insert(key, value);
+
+        Ok(())
+    }
+
+    fn put_batch(&mut self, subtree: HashMap<DBKey, Value>) -> PmtreeResult<()> {
+        self.0.extend(subtree);
+
+        Ok(())
+    }
+
+    fn delete(&mut self, key: DBKey) -> PmtreeResult<()> {
+        self.0.remove(&key);
+
+        Ok(())
+    }
+
+    fn close(&mut self) -> PmtreeResult<()> {
+        Ok(())
+    }
+}
+
+impl Hasher for MyKeccak {
+    type Fr = [u8; 32];
+
+    const ID: &'static str = "keccak";
+
+    fn serialize(value: Self::Fr) -> Value {
+        value.to_vec()
+    }
+
+    fn deserialize(value: Value) -> PmtreeResult<Self::Fr> {
+        value
+            .try_into()
+            .map_err(|_| PmtreeErrorKind::CustomError(String::from("Invalid value length")))
+    }
+
+    fn hash(&self, input: &[Self::Fr]) -> Self::Fr {
+        let mut output = [0; 32];
+        let mut hasher = Keccak::v256();
+        for element in input {
+            hasher.update(element);
+        }
+        hasher.finalize(&mut output);
+        output
+    }
+}
+
+fn batch_insert(c: &mut Criterion) {
+    let leaves: Vec<[u8; 32]> = (0..1u32 << 16)
+        .map(|i| {
+            let mut leaf = [0; 32];
+            leaf[28..].copy_from_slice(&i.to_be_bytes());
+            leaf
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("batch_insert_65536");
+    group.sample_size(10);
+
+    let max_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let threads = std::iter::successors(Some(1), |n| Some(n * 2)).take_while(|&n| n <= max_threads);
+
+    for num_threads in threads {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(num_threads),
+            &num_threads,
+            |b, &num_threads| {
+                b.iter_batched(
+                    || {
+                        let mut mt =
+                            MerkleTree::<MemoryDB, MyKeccak>::new(20, MemoryDBConfig).unwrap();
+                        mt.set_num_threads(num_threads).unwrap();
+                        mt
+                    },
+                    |mut mt| mt.batch_insert(None, &leaves).unwrap(),
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, batch_insert);
+criterion_main!(benches);
@@ -0,0 +1,165 @@
+use crate::*;
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+/// Trait that must be implemented for a non-blocking Database.
+/// Mirrors `Database`, every call returns a future
+pub trait AsyncDatabase: Send + Sync {
+    /// Config for database. Default is necessary for a default() pmtree function
+    type Config: Default + Send;
+
+    /// Creates new instance of db
+    fn new(config: Self::Config) -> impl Future<Output = PmtreeResult<Self>> + Send
+    where
+        Self: Sized;
+
+    /// Loades existing db (existence check required)
+    fn load(config: Self::Config) -> impl Future<Output = PmtreeResult<Self>> + Send
+    where
+        Self: Sized;
+
+    /// Returns value from db by the key
+    fn get(&self, key: DBKey) -> impl Future<Output = PmtreeResult<Option<Value>>> + Send;
+
+    /// Returns values from db by the keys, in the same order.
+    /// Backends supporting multi-get should override it
+    fn get_batch(
+        &self,
+        keys: &[DBKey],
+    ) -> impl Future<Output = PmtreeResult<Vec<Option<Value>>>> + Send {
+        async move {
+            let mut values = Vec::with_capacity(keys.len());
+            for &key in keys {
+                values.push(self.get(key).await?);
+            }
+
+            Ok(values)
+        }
+    }
+
+    /// Puts the value to the db by the key
+    fn put(&mut self, key: DBKey, value: Value) -> impl Future<Output = PmtreeResult<()>> + Send;
+
+    /// Puts the leaves batch to the db
+    fn put_batch(
+        &mut self,
+        subtree: HashMap<DBKey, Value>,
+    ) -> impl Future<Output = PmtreeResult<()>> + Send;
+
+    /// Deletes the value from the db by the key (deleting a missing key is not an error)
+    fn delete(&mut self, key: DBKey) -> impl Future<Output = PmtreeResult<()>> + Send;
+
+    /// Deletes the batch of keys from the db
+    fn delete_batch(&mut self, keys: Vec<DBKey>) -> impl Future<Output = PmtreeResult<()>> + Send {
+        async move {
+            for key in keys {
+                self.delete(key).await?;
+            }
+
+            Ok(())
+        }
+    }
+
+    /// Closes the db connection
+    fn close(&mut self) -> impl Future<Output = PmtreeResult<()>> + Send;
+}
+
+/// Adapts a synchronous `Database` to `AsyncDatabase`:
+/// every call runs on the tokio blocking pool, so it never blocks the executor
+pub struct BlockingDatabase<D>(Arc<Mutex<D>>);
+
+impl<D> BlockingDatabase<D> {
+    /// Returns the shared handle of the wrapped db, usable by synchronous trees as well
+    pub fn shared(&self) -> Arc<Mutex<D>> {
+        Arc::clone(&self.0)
+    }
+
+    /// Consumes the adapter, returning the db if it isn't shared anymore
+    pub fn into_inner(self) -> Option<D> {
+        Arc::try_unwrap(self.0)
+            .ok()
+            .and_then(|db| db.into_inner().ok())
+    }
+}
+
+impl<D> From<D> for BlockingDatabase<D> {
+    fn from(db: D) -> Self {
+        Self(Arc::new(Mutex::new(db)))
+    }
+}
+
+impl<D> From<Arc<Mutex<D>>> for BlockingDatabase<D> {
+    fn from(db: Arc<Mutex<D>>) -> Self {
+        Self(db)
+    }
+}
+
+impl<D> AsyncDatabase for BlockingDatabase<D>
+where
+    D: Database + Send + 'static,
+    D::Config: Send + 'static,
+{
+    type Config = D::Config;
+
+    async fn new(config: Self::Config) -> PmtreeResult<Self> {
+        let db = spawn_blocking(move || D::new(config)).await?;
+
+        Ok(Self::from(db))
+    }
+
+    async fn load(config: Self::Config) -> PmtreeResult<Self> {
+        let db = spawn_blocking(move || D::load(config)).await?;
+
+        Ok(Self::from(db))
+    }
+
+    async fn get(&self, key: DBKey) -> PmtreeResult<Option<Value>> {
+        let db = self.shared();
+        spawn_blocking(move || db.get(key)).await
+    }
+
+    async fn get_batch(&self, keys: &[DBKey]) -> PmtreeResult<Vec<Option<Value>>> {
+        let (db, keys) = (self.shared(), keys.to_vec());
+        spawn_blocking(move || db.get_batch(&keys)).await
+    }
+
+    async fn put(&mut self, key: DBKey, value: Value) -> PmtreeResult<()> {
+        let mut db = self.shared();
+        spawn_blocking(move || db.put(key, value)).await
+    }
+
+    async fn put_batch(&mut self, subtree: HashMap<DBKey, Value>) -> PmtreeResult<()> {
+        let mut db = self.shared();
+        spawn_blocking(move || db.put_batch(subtree)).await
+    }
+
+    async fn delete(&mut self, key: DBKey) -> PmtreeResult<()> {
+        let mut db = self.shared();
+        spawn_blocking(move || db.delete(key)).await
+    }
+
+    async fn delete_batch(&mut self, keys: Vec<DBKey>) -> PmtreeResult<()> {
+        let mut db = self.shared();
+        spawn_blocking(move || db.delete_batch(keys)).await
+    }
+
+    async fn close(&mut self) -> PmtreeResult<()> {
+        let mut db = self.shared();
+        spawn_blocking(move || db.close()).await
+    }
+}
+
+// Runs the blocking db call on the tokio blocking pool
+async fn spawn_blocking<T, F>(f: F) -> PmtreeResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> PmtreeResult<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await.map_err(|e| {
+        PmtreeErrorKind::DatabaseError(DatabaseErrorKind::CustomError(format!(
+            "Blocking db task failed: {e}"
+        )))
+    })?
+}
@@ -0,0 +1,401 @@
+use crate::free_list::FreeList;
+use crate::leaf_index::LeafIndexUpdate;
+use crate::state::{check_batch, TreeState, Update, Writes};
+use crate::tree::*;
+use crate::*;
+
+use std::mem;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// The Merkle Tree working on a non-blocking db.
+/// Mirrors `MerkleTree` and shares its storage layout, so a tree stored by one
+/// can be loaded by the other. Batch hashing runs on the tokio blocking pool
+pub struct AsyncMerkleTree<D, H>
+where
+    D: AsyncDatabase,
+    H: Hasher,
+{
+    db: D,
+    state: TreeState<H>,
+}
+
+impl<D, H> AsyncMerkleTree<D, H>
+where
+    D: AsyncDatabase,
+    H: Hasher,
+{
+    /// Creates new `AsyncMerkleTree` and store it to the specified path/db
+    pub async fn new(depth: usize, db_config: D::Config) -> PmtreeResult<Self>
+    where
+        H: Default,
+    {
+        Self::with_hasher(depth, db_config, H::default()).await
+    }
+
+    /// Creates new `AsyncMerkleTree` that hashes through the specified hasher instance
+    pub async fn with_hasher(depth: usize, db_config: D::Config, hasher: H) -> PmtreeResult<Self> {
+        Self::create(D::new(db_config).await?, DEFAULT_TREE_ID, depth, hasher).await
+    }
+
+    /// Creates new `AsyncMerkleTree` with the specified id in the (possibly shared) db
+    pub async fn new_in(db: D, tree_id: TreeId, depth: usize) -> PmtreeResult<Self>
+    where
+        H: Default,
+    {
+        Self::new_in_with_hasher(db, tree_id, depth, H::default()).await
+    }
+
+    /// Creates new `AsyncMerkleTree` with the specified id in the (possibly shared) db
+    /// that hashes through the specified hasher instance
+    pub async fn new_in_with_hasher(
+        db: D,
+        tree_id: TreeId,
+        depth: usize,
+        hasher: H,
+    ) -> PmtreeResult<Self> {
+        Self::create(db, tree_id, depth, hasher).await
+    }
+
+    // Creates the tree in the db namespace
+    async fn create(db: D, tree_id: TreeId, depth: usize, hasher: H) -> PmtreeResult<Self> {
+        let values = db.get_batch(&TreeState::<H>::create_keys(tree_id)).await?;
+        let (state, writes) = TreeState::create(tree_id, depth, hasher, values)?;
+
+        let mut tree = Self { db, state };
+        tree.write(writes).await?;
+
+        Ok(tree)
+    }
+
+    /// Loads existing Merkle Tree from the specified path/db
+    pub async fn load(db_config: D::Config) -> PmtreeResult<Self>
+    where
+        H: Default,
+    {
+        Self::load_with_hasher(db_config, H::default()).await
+    }
+
+    /// Loads existing Merkle Tree that hashes through the specified hasher instance
+    pub async fn load_with_hasher(db_config: D::Config, hasher: H) -> PmtreeResult<Self> {
+        Self::open(D::load(db_config).await?, DEFAULT_TREE_ID, hasher).await
+    }
+
+    /// Loads existing Merkle Tree with the specified id from the (possibly shared) db
+    pub async fn load_in(db: D, tree_id: TreeId) -> PmtreeResult<Self>
+    where
+        H: Default,
+    {
+        Self::load_in_with_hasher(db, tree_id, H::default()).await
+    }
+
+    /// Loads existing Merkle Tree with the specified id from the (possibly shared) db
+    /// that hashes through the specified hasher instance
+    pub async fn load_in_with_hasher(db: D, tree_id: TreeId, hasher: H) -> PmtreeResult<Self> {
+        Self::open(db, tree_id, hasher).await
+    }
+
+    // Opens the tree stored in the db namespace, validating its metadata
+    async fn open(db: D, tree_id: TreeId, hasher: H) -> PmtreeResult<Self> {
+        let values = db.get_batch(&TreeState::<H>::open_keys(tree_id)).await?;
+        let state = TreeState::open(tree_id, hasher, None, values)?;
+
+        Ok(Self { db, state })
+    }
+
+    /// Closes the db connection
+    pub async fn close(&mut self) -> PmtreeResult<()> {
+        self.db.close().await
+    }
+
+    /// Returns the db
+    pub fn db(&self) -> &D {
+        &self.db
+    }
+
+    /// Consumes the tree, returning the db
+    pub fn into_inner(self) -> D {
+        self.db
+    }
+
+    /// Sets a leaf at the specified tree index
+    pub async fn set(&mut self, key: usize, leaf: H::Fr) -> PmtreeResult<()> {
+        // Read the path siblings and the replaced leaf in one batch
+        let keys = self.state.set_keys(key)?;
+        let siblings = self.get_elems(&keys).await?;
+
+        let (root, nodes, old_leaf) = self.state.set(key, leaf, siblings);
+
+        self.commit(root, nodes, key, &[old_leaf], &[leaf]).await
+    }
+
+    // Writes the root and the nodes with the leaf index, the free list, the number of
+    // non-default leaves and next_index after the run of `old_leaves` starting at `start`
+    // is replaced by `leaves`
+    async fn commit(
+        &mut self,
+        root: H::Fr,
+        nodes: Nodes<H>,
+        start: usize,
+        old_leaves: &[H::Fr],
+        leaves: &[H::Fr],
+    ) -> PmtreeResult<()> {
+        let mut update = self.state.nodes_update(root, nodes);
+        if let Some(leaf_index) = self.state.leaf_index_update(start, old_leaves, leaves) {
+            update
+                .writes
+                .extend(self.leaf_index_writes(leaf_index).await?);
+        }
+        self.state
+            .commit_leaves(&mut update, start, old_leaves, leaves);
+
+        self.apply(update).await
+    }
+
+    // Stores the writes of the update, the in-memory state changes only once they succeed
+    async fn apply(&mut self, mut update: Update<H>) -> PmtreeResult<()> {
+        self.write(mem::take(&mut update.writes)).await?;
+        self.state.apply(update);
+
+        Ok(())
+    }
+
+    // Applies the writes to the db
+    async fn write(&mut self, writes: Writes) -> PmtreeResult<()> {
+        if !writes.puts.is_empty() {
+            self.db.put_batch(writes.puts).await?;
+        }
+        if !writes.deletes.is_empty() {
+            self.db.delete_batch(writes.deletes).await?;
+        }
+
+        Ok(())
+    }
+
+    // Returns elems by the keys, the ones missing in the node cache are read in one db batch
+    async fn get_elems(&self, keys: &[Key]) -> PmtreeResult<Vec<H::Fr>> {
+        let (cached, missing) = self.state.cached_nodes(keys);
+        let read = if missing.is_empty() {
+            Vec::new()
+        } else {
+            self.read_nodes(&missing).await?
+        };
+
+        Ok(self.state.fill_nodes(cached, &missing, read))
+    }
+
+    // Reads the nodes from the db in one batch, bypassing the node cache
+    async fn read_nodes(&self, keys: &[Key]) -> PmtreeResult<Vec<H::Fr>> {
+        let values = self.db.get_batch(&self.state.db_keys(keys)).await?;
+
+        self.state.decode_nodes(keys, values)
+    }
+
+    /// Deletes a leaf at the `key` by setting it to its default value
+    pub async fn delete(&mut self, key: usize) -> PmtreeResult<()> {
+        if key >= self.state.next_index {
+            return Err(PmtreeErrorKind::TreeError(TreeErrorKind::InvalidKey));
+        }
+
+        self.set(key, *self.state.default_leaf()).await
+    }
+
+    /// Inserts a leaf to the next available index
+    pub async fn update_next(&mut self, leaf: H::Fr) -> PmtreeResult<()> {
+        self.set(self.state.next_index, leaf).await
+    }
+
+    /// Inserts a leaf to the lowest freed index if the free list is enabled and not empty,
+    /// to the next available index otherwise. Returns the index of the leaf
+    pub async fn insert(&mut self, leaf: H::Fr) -> PmtreeResult<usize> {
+        let index = self
+            .state
+            .free_list
+            .as_ref()
+            .and_then(FreeList::first)
+            .unwrap_or(self.state.next_index);
+
+        self.set(index, leaf).await?;
+
+        Ok(index)
+    }
+
+    /// Enables the free list, see `MerkleTree::enable_free_list`
+    pub async fn enable_free_list(&mut self) -> PmtreeResult<()> {
+        if self.state.free_list.is_some() {
+            return Ok(());
+        }
+
+        let keys = self.state.leaf_keys(0..self.state.next_index);
+        let leaves = self.read_nodes(&keys).await?;
+        let update = self.state.enable_free_list(&leaves);
+
+        self.apply(update).await
+    }
+
+    /// Batch insertion, the siblings on the edges and the replaced leaves are read in one db batch.
+    /// Hashing runs on the tokio blocking pool, in parallel on the tree thread pool
+    /// (the global rayon pool by default) from the parallel threshold
+    pub async fn batch_insert(&mut self, start: Option<usize>, leaves: &[H::Fr]) -> PmtreeResult<()>
+    where
+        H: 'static,
+    {
+        let start = start.unwrap_or(self.state.next_index);
+        let keys = self.state.batch_keys(start, leaves.len())?;
+
+        if leaves.is_empty() {
+            return Ok(());
+        }
+
+        // Read the replaced leaves in the same batch as the edges
+        let values = self.get_elems(&keys).await?;
+        let (recalculation, old_leaves) =
+            self.state
+                .recalculation(start, leaves.to_vec(), keys, values);
+
+        let (root, nodes) = tokio::task::spawn_blocking(move || recalculation.run())
+            .await
+            .map_err(|e| {
+                PmtreeErrorKind::TreeError(TreeErrorKind::CustomError(format!(
+                    "Batch hashing task failed: {e}"
+                )))
+            })?;
+
+        self.commit(root, nodes, start, &old_leaves, leaves).await
+    }
+
+    /// Enables the leaf-to-index secondary index, see `MerkleTree::enable_leaf_index`
+    pub async fn enable_leaf_index(&mut self) -> PmtreeResult<()> {
+        if self.state.leaf_index {
+            return Ok(());
+        }
+
+        let keys = self.state.leaf_keys(0..self.state.next_index);
+        let leaves = self.read_nodes(&keys).await?;
+        let mut update = self.state.enable_leaf_index();
+        update.writes.extend(
+            self.leaf_index_writes(self.state.index_leaves(&leaves))
+                .await?,
+        );
+
+        self.apply(update).await
+    }
+
+    // Reads the buckets touched by the update in one batch, returning their writes
+    async fn leaf_index_writes(&self, update: LeafIndexUpdate) -> PmtreeResult<Writes> {
+        let keys = update.bucket_keys();
+        if keys.is_empty() {
+            return Ok(Writes::default());
+        }
+
+        let values = check_batch(keys.len(), self.db.get_batch(&keys).await?)?;
+        update.apply(keys, values)
+    }
+
+    /// Returns the lowest index holding the leaf, requires the leaf index to be enabled.
+    /// Default leaves aren't indexed
+    pub async fn index_of(&self, leaf: &H::Fr) -> PmtreeResult<Option<usize>> {
+        let bucket = self.db.get(self.state.bucket_key(leaf)?).await?;
+
+        self.state.find_leaf(leaf, bucket)
+    }
+
+    /// Returns whether the leaf index is enabled
+    pub fn has_leaf_index(&self) -> bool {
+        self.state.leaf_index
+    }
+
+    /// Computes a Merkle proof for the leaf at the specified index
+    pub async fn proof(&self, index: usize) -> PmtreeResult<MerkleProof<H>> {
+        let keys = self.state.proof_keys(index)?;
+        let siblings = self.get_elems(&keys).await?;
+
+        Ok(self.state.proof(&keys, siblings))
+    }
+
+    /// Verifies a Merkle proof with respect to the input leaf and the tree root,
+    /// rejecting proofs whose length differs from the tree depth
+    pub fn verify(&self, leaf: &H::Fr, witness: &MerkleProof<H>) -> bool {
+        self.state.verify(leaf, witness)
+    }
+
+    /// Returns the leaf by the key
+    pub async fn get(&self, key: usize) -> PmtreeResult<H::Fr> {
+        if key >= self.capacity() {
+            return Err(PmtreeErrorKind::TreeError(TreeErrorKind::IndexOutOfBounds));
+        }
+
+        Ok(self.get_elems(&[Key(self.state.depth, key)]).await?[0])
+    }
+
+    /// Returns the root of the tree
+    pub fn root(&self) -> H::Fr {
+        self.state.root
+    }
+
+    /// Returns next_index, i.e. the number of leaves up to the highest set one.
+    /// See `occupied_count` for the number of non-default leaves
+    pub fn leaves_set(&self) -> usize {
+        self.state.next_index
+    }
+
+    /// Returns the ranges of freed indexes below next_index, in ascending order.
+    /// Empty if the free list isn't enabled
+    pub fn free_slots(&self) -> Vec<Range<usize>> {
+        self.state
+            .free_list
+            .iter()
+            .flat_map(FreeList::ranges)
+            .collect()
+    }
+
+    /// Returns the number of leaves that differ from the default leaf
+    pub fn occupied_count(&self) -> usize {
+        self.state.occupied
+    }
+
+    /// Returns the capacity of the tree, i.e. the maximum number of leaves
+    pub fn capacity(&self) -> usize {
+        self.state.capacity()
+    }
+
+    /// Returns the id of the tree inside the db
+    pub fn tree_id(&self) -> TreeId {
+        self.state.tree_id
+    }
+
+    /// Returns the depth of the tree
+    pub fn depth(&self) -> usize {
+        self.state.depth
+    }
+
+    /// Sets the thread pool used by batch insertion (the global rayon pool by default)
+    pub fn set_thread_pool(&mut self, pool: Arc<rayon::ThreadPool>) {
+        self.state.thread_pool = Some(pool);
+    }
+
+    /// Builds a dedicated thread pool with the specified number of threads for batch insertion
+    pub fn set_num_threads(&mut self, num_threads: usize) -> PmtreeResult<()> {
+        self.state.set_num_threads(num_threads)
+    }
+
+    /// Sets the minimal number of leaves for which batch insertion hashes in parallel
+    pub fn set_parallel_threshold(&mut self, threshold: usize) {
+        self.state.parallel_threshold = threshold;
+    }
+
+    /// Enables the in-memory node cache, see `MerkleTree::set_node_cache`
+    pub fn set_node_cache(&mut self, pinned_levels: usize, capacity: usize) {
+        self.state.set_node_cache(pinned_levels, capacity);
+    }
+
+    /// Returns the node cache statistics, if the cache is enabled
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.state.cache_stats()
+    }
+
+    /// Returns the hasher instance used by the tree
+    pub fn hasher(&self) -> &H {
+        &self.state.hasher
+    }
+}
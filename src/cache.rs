@@ -0,0 +1,117 @@
+use crate::tree::Key;
+
+use std::collections::{BTreeMap, HashMap};
+
+/// Statistics of the in-memory node cache
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of reads served from the cache
+    pub hits: u64,
+    /// Number of reads that went to the db
+    pub misses: u64,
+    /// Number of nodes pinned in the top levels
+    pub pinned: usize,
+    /// Number of nodes in the LRU part
+    pub cached: usize,
+}
+
+impl CacheStats {
+    /// Returns the share of reads served from the cache
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            return 0.0;
+        }
+
+        self.hits as f64 / total as f64
+    }
+}
+
+// In-memory node cache: nodes of the top levels are pinned,
+// the rest are kept in an LRU of the fixed capacity
+pub(crate) struct NodeCache<V> {
+    pinned_levels: usize,
+    pinned: HashMap<Key, V>,
+    capacity: usize,
+    entries: HashMap<Key, (V, u64)>,
+    // Recency order: tick of the last access -> key
+    order: BTreeMap<u64, Key>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl<V: Copy> NodeCache<V> {
+    pub(crate) fn new(pinned_levels: usize, capacity: usize) -> Self {
+        Self {
+            pinned_levels,
+            pinned: HashMap::new(),
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    // Returns the cached node, updating its recency
+    pub(crate) fn get(&mut self, key: &Key) -> Option<V> {
+        let value = if key.0 < self.pinned_levels {
+            self.pinned.get(key).copied()
+        } else {
+            self.touch(key)
+        };
+
+        match value {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+
+        value
+    }
+
+    // Inserts or updates the node, evicting the least recently used one if needed
+    pub(crate) fn insert(&mut self, key: Key, value: V) {
+        if key.0 < self.pinned_levels {
+            self.pinned.insert(key, value);
+            return;
+        }
+
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.tick += 1;
+        if let Some((_, tick)) = self.entries.insert(key, (value, self.tick)) {
+            self.order.remove(&tick);
+        }
+        self.order.insert(self.tick, key);
+
+        if self.entries.len() > self.capacity {
+            if let Some((_, evicted)) = self.order.pop_first() {
+                self.entries.remove(&evicted);
+            }
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            pinned: self.pinned.len(),
+            cached: self.entries.len(),
+        }
+    }
+
+    fn touch(&mut self, key: &Key) -> Option<V> {
+        let (value, tick) = self.entries.get_mut(key)?;
+        self.order.remove(tick);
+
+        self.tick += 1;
+        *tick = self.tick;
+        self.order.insert(self.tick, *key);
+
+        Some(*value)
+    }
+}
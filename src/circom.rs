@@ -0,0 +1,138 @@
+//! Rendering of Merkle proofs as circom/snarkjs circuit inputs
+
+use crate::tree::MerkleProof;
+use crate::*;
+
+use std::fmt::Write;
+
+/// Merkle proof inputs of a circom circuit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CircomInput {
+    /// Formatted path elements, from the leaf level to the root
+    pub path_elements: Vec<String>,
+    /// Path indexes, from the leaf level to the root
+    pub identity_path_index: Vec<u8>,
+}
+
+impl CircomInput {
+    /// Renders the inputs as the witness JSON object:
+    /// `{"pathElements":["..",..],"identityPathIndex":[..]}`
+    pub fn to_json(&self) -> String {
+        let elements: Vec<String> = self
+            .path_elements
+            .iter()
+            .map(|element| format!("\"{}\"", escape(element)))
+            .collect();
+        let indexes: Vec<String> = self.identity_path_index.iter().map(u8::to_string).collect();
+
+        format!(
+            "{{\"pathElements\":[{}],\"identityPathIndex\":[{}]}}",
+            elements.join(","),
+            indexes.join(",")
+        )
+    }
+}
+
+impl<H: Hasher> MerkleProof<H> {
+    /// Returns the circuit inputs of the proof, formatting every serialized path element
+    /// with `format` (e.g. `circom::decimal_be`)
+    pub fn to_circom_input<F>(&self, format: F) -> CircomInput
+    where
+        F: Fn(&[u8]) -> String,
+    {
+        CircomInput {
+            path_elements: self
+                .get_path_elements()
+                .into_iter()
+                .map(|element| format(&H::serialize(element)))
+                .collect(),
+            identity_path_index: self.get_path_index(),
+        }
+    }
+
+    /// Returns the circuit inputs of the proof padded to `depth` levels
+    /// with default leaves (`H::default_leaf()`) and zero path indexes, for circuits of a fixed depth
+    pub fn to_circom_input_padded<F>(&self, depth: usize, format: F) -> PmtreeResult<CircomInput>
+    where
+        F: Fn(&[u8]) -> String,
+    {
+        if depth < self.length() {
+            return Err(PmtreeErrorKind::TreeError(TreeErrorKind::DepthMismatch {
+                expected: depth,
+                actual: self.length(),
+            }));
+        }
+
+        let mut input = self.to_circom_input(&format);
+        let padding = format(&H::serialize(H::default_leaf()));
+        input.path_elements.resize(depth, padding);
+        input.identity_path_index.resize(depth, 0);
+
+        Ok(input)
+    }
+
+    /// Renders the proof as the circom witness JSON, see `CircomInput::to_json`
+    pub fn to_circom_json<F>(&self, format: F) -> String
+    where
+        F: Fn(&[u8]) -> String,
+    {
+        self.to_circom_input(format).to_json()
+    }
+}
+
+/// Formats big-endian bytes as a decimal number
+pub fn decimal_be(bytes: &[u8]) -> String {
+    // Little-endian limbs of 9 decimal digits
+    let mut limbs: Vec<u32> = vec![0];
+    for &byte in bytes {
+        let mut carry = byte as u64;
+        for limb in limbs.iter_mut() {
+            let value = *limb as u64 * 256 + carry;
+            *limb = (value % 1_000_000_000) as u32;
+            carry = value / 1_000_000_000;
+        }
+        while carry > 0 {
+            limbs.push((carry % 1_000_000_000) as u32);
+            carry /= 1_000_000_000;
+        }
+    }
+
+    let mut decimal = limbs.last().unwrap().to_string();
+    for limb in limbs.iter().rev().skip(1) {
+        write!(decimal, "{limb:09}").unwrap();
+    }
+
+    decimal
+}
+
+/// Formats little-endian bytes (e.g. arkworks field elements) as a decimal number
+pub fn decimal_le(bytes: &[u8]) -> String {
+    let reversed: Vec<u8> = bytes.iter().rev().copied().collect();
+    decimal_be(&reversed)
+}
+
+/// Formats big-endian bytes as a 0x-prefixed hex number
+pub fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(2 + bytes.len() * 2);
+    hex.push_str("0x");
+    for byte in bytes {
+        write!(hex, "{byte:02x}").unwrap();
+    }
+
+    hex
+}
+
+// Escapes the string for a JSON string literal
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
@@ -1,6 +1,7 @@
 use crate::*;
 
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
 
 /// Trait that must be implemented for a Database
 pub trait Database {
@@ -20,12 +21,147 @@ pub trait Database {
     /// Returns value from db by the key
     fn get(&self, key: DBKey) -> PmtreeResult<Option<Value>>;
 
+    /// Returns values from db by the keys, in the same order.
+    /// Backends supporting multi-get should override it
+    fn get_batch(&self, keys: &[DBKey]) -> PmtreeResult<Vec<Option<Value>>> {
+        keys.iter().map(|&key| self.get(key)).collect()
+    }
+
     /// Puts the value to the db by the key
     fn put(&mut self, key: DBKey, value: Value) -> PmtreeResult<()>;
 
     /// Puts the leaves batch to the db
     fn put_batch(&mut self, subtree: HashMap<DBKey, Value>) -> PmtreeResult<()>;
 
+    /// Deletes the value from the db by the key (deleting a missing key is not an error)
+    fn delete(&mut self, key: DBKey) -> PmtreeResult<()>;
+
+    /// Deletes the batch of keys from the db
+    fn delete_batch(&mut self, keys: Vec<DBKey>) -> PmtreeResult<()> {
+        for key in keys {
+            self.delete(key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the entries with keys in `[from, to)`, ordered by key.
+    /// Optional capability, unsupported by default
+    fn iter_range(&self, _from: DBKey, _to: DBKey) -> PmtreeResult<DBIterator<'_>> {
+        Err(PmtreeErrorKind::DatabaseError(
+            DatabaseErrorKind::UnsupportedOperation("iter_range"),
+        ))
+    }
+
     /// Closes the db connection
     fn close(&mut self) -> PmtreeResult<()>;
 }
+
+/// Borrowed db: lets a tree work on an already opened connection.
+/// Borrowed db can't be created or loaded from config
+impl<D: Database> Database for &mut D {
+    type Config = D::Config;
+
+    fn new(_config: Self::Config) -> PmtreeResult<Self> {
+        Err(PmtreeErrorKind::DatabaseError(
+            DatabaseErrorKind::UnsupportedOperation("new"),
+        ))
+    }
+
+    fn load(_config: Self::Config) -> PmtreeResult<Self> {
+        Err(PmtreeErrorKind::DatabaseError(
+            DatabaseErrorKind::UnsupportedOperation("load"),
+        ))
+    }
+
+    fn get(&self, key: DBKey) -> PmtreeResult<Option<Value>> {
+        (**self).get(key)
+    }
+
+    fn get_batch(&self, keys: &[DBKey]) -> PmtreeResult<Vec<Option<Value>>> {
+        (**self).get_batch(keys)
+    }
+
+    fn put(&mut self, key: DBKey, value: Value) -> PmtreeResult<()> {
+        (**self).put(key, value)
+    }
+
+    fn put_batch(&mut self, subtree: HashMap<DBKey, Value>) -> PmtreeResult<()> {
+        (**self).put_batch(subtree)
+    }
+
+    fn delete(&mut self, key: DBKey) -> PmtreeResult<()> {
+        (**self).delete(key)
+    }
+
+    fn delete_batch(&mut self, keys: Vec<DBKey>) -> PmtreeResult<()> {
+        (**self).delete_batch(keys)
+    }
+
+    fn iter_range(&self, from: DBKey, to: DBKey) -> PmtreeResult<DBIterator<'_>> {
+        (**self).iter_range(from, to)
+    }
+
+    fn close(&mut self) -> PmtreeResult<()> {
+        (**self).close()
+    }
+}
+
+/// Shared db: several trees can work on one connection, which outlives them
+impl<D: Database> Database for Arc<Mutex<D>> {
+    type Config = D::Config;
+
+    fn new(config: Self::Config) -> PmtreeResult<Self> {
+        Ok(Arc::new(Mutex::new(D::new(config)?)))
+    }
+
+    fn load(config: Self::Config) -> PmtreeResult<Self> {
+        Ok(Arc::new(Mutex::new(D::load(config)?)))
+    }
+
+    fn get(&self, key: DBKey) -> PmtreeResult<Option<Value>> {
+        lock(self)?.get(key)
+    }
+
+    fn get_batch(&self, keys: &[DBKey]) -> PmtreeResult<Vec<Option<Value>>> {
+        lock(self)?.get_batch(keys)
+    }
+
+    fn put(&mut self, key: DBKey, value: Value) -> PmtreeResult<()> {
+        lock(self)?.put(key, value)
+    }
+
+    fn put_batch(&mut self, subtree: HashMap<DBKey, Value>) -> PmtreeResult<()> {
+        lock(self)?.put_batch(subtree)
+    }
+
+    fn delete(&mut self, key: DBKey) -> PmtreeResult<()> {
+        lock(self)?.delete(key)
+    }
+
+    fn delete_batch(&mut self, keys: Vec<DBKey>) -> PmtreeResult<()> {
+        lock(self)?.delete_batch(keys)
+    }
+
+    // Entries are collected while the lock is held
+    fn iter_range(&self, from: DBKey, to: DBKey) -> PmtreeResult<DBIterator<'_>> {
+        let entries = lock(self)?
+            .iter_range(from, to)?
+            .collect::<PmtreeResult<Vec<_>>>()?;
+
+        Ok(Box::new(entries.into_iter().map(Ok)))
+    }
+
+    fn close(&mut self) -> PmtreeResult<()> {
+        lock(self)?.close()
+    }
+}
+
+// Locks the shared db
+fn lock<D>(db: &Mutex<D>) -> PmtreeResult<MutexGuard<'_, D>> {
+    db.lock().map_err(|_| {
+        PmtreeErrorKind::DatabaseError(DatabaseErrorKind::CustomError(String::from(
+            "Shared db lock is poisoned",
+        )))
+    })
+}
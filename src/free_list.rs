@@ -0,0 +1,131 @@
+use crate::*;
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+// Freed leaf indexes, kept as disjoint and non-adjacent ranges (start -> end),
+// so a sparse tree doesn't store every skipped index
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct FreeList(BTreeMap<usize, usize>);
+
+impl FreeList {
+    // Decodes the free list: every range is start (8 bytes) | end (8 bytes), ordered by start
+    pub(crate) fn decode(value: Value) -> PmtreeResult<Self> {
+        let malformed =
+            || PmtreeErrorKind::DatabaseError(DatabaseErrorKind::MalformedMetadata("free list"));
+
+        if !value.len().is_multiple_of(16) {
+            return Err(malformed());
+        }
+
+        let mut ranges = BTreeMap::new();
+        let mut last_end = None;
+        for range in value.chunks(16) {
+            let start = u64::from_be_bytes(range[..8].try_into().unwrap());
+            let end = u64::from_be_bytes(range[8..].try_into().unwrap());
+            let start: usize = start.try_into().map_err(|_| malformed())?;
+            let end: usize = end.try_into().map_err(|_| malformed())?;
+
+            if start >= end || last_end.is_some_and(|last_end| start <= last_end) {
+                return Err(malformed());
+            }
+
+            ranges.insert(start, end);
+            last_end = Some(end);
+        }
+
+        Ok(Self(ranges))
+    }
+
+    // Encodes the free list
+    pub(crate) fn encode(&self) -> Value {
+        self.0
+            .iter()
+            .flat_map(|(&start, &end)| [start as u64, end as u64])
+            .flat_map(u64::to_be_bytes)
+            .collect()
+    }
+
+    // Returns the lowest freed index
+    pub(crate) fn first(&self) -> Option<usize> {
+        self.0.first_key_value().map(|(&start, _)| start)
+    }
+
+    // Returns the index past the highest freed one, 0 if nothing is freed
+    pub(crate) fn end(&self) -> usize {
+        self.0.last_key_value().map_or(0, |(_, &end)| end)
+    }
+
+    // Returns the freed ranges in ascending order
+    pub(crate) fn ranges(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        self.0.iter().map(|(&start, &end)| start..end)
+    }
+
+    // Frees the range, merging it with the touching ones. Returns whether the list changed
+    pub(crate) fn insert(&mut self, range: Range<usize>) -> bool {
+        if range.is_empty() {
+            return false;
+        }
+
+        let (mut start, mut end) = (range.start, range.end);
+        if let Some((&prev_start, &prev_end)) = self.0.range(..=start).next_back() {
+            if prev_end >= end {
+                return false;
+            }
+            if prev_end >= start {
+                start = prev_start;
+            }
+        }
+
+        let touching: Vec<usize> = self.0.range(start..=end).map(|(&start, _)| start).collect();
+        for touching_start in touching {
+            end = end.max(self.0.remove(&touching_start).unwrap());
+        }
+        self.0.insert(start, end);
+
+        true
+    }
+
+    // Takes the index out of the list. Returns whether the list changed
+    pub(crate) fn remove(&mut self, index: usize) -> bool {
+        let Some((&start, &end)) = self.0.range(..=index).next_back() else {
+            return false;
+        };
+
+        if end <= index {
+            return false;
+        }
+
+        self.0.remove(&start);
+        if start < index {
+            self.0.insert(start, index);
+        }
+        if index + 1 < end {
+            self.0.insert(index + 1, end);
+        }
+
+        true
+    }
+
+    // Updates the list for the run of `leaves` set starting at `start`, before next_index
+    // is moved past the run: the skipped leaves and the default ones are freed, others are taken.
+    // Returns whether the list changed
+    pub(crate) fn update<F: PartialEq>(
+        &mut self,
+        next_index: usize,
+        start: usize,
+        leaves: &[F],
+        default: &F,
+    ) -> bool {
+        let mut changed = self.insert(next_index..start);
+        for (index, leaf) in (start..).zip(leaves) {
+            changed |= if leaf == default {
+                self.insert(index..index + 1)
+            } else {
+                self.remove(index)
+            };
+        }
+
+        changed
+    }
+}
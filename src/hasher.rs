@@ -2,16 +2,20 @@ use crate::*;
 
 use std::fmt::Debug;
 
-/// Trait that must be implemented for Hash Function
-pub trait Hasher {
+/// Trait that must be implemented for Hash Function.
+/// Hashing goes through an instance, so keyed or runtime-parametrized hashers are possible
+pub trait Hasher: Send + Sync {
     /// Native type for the hash-function
     type Fr: Copy + Eq + Default + Sync + Send + Debug;
 
+    /// Identifier of the hash-function, stored in the tree header
+    const ID: &'static str;
+
     /// Serializes Self::Fr
     fn serialize(value: Self::Fr) -> Value;
 
-    /// Deserializes Self::Fr
-    fn deserialize(value: Value) -> Self::Fr;
+    /// Deserializes Self::Fr, failing on malformed values
+    fn deserialize(value: Value) -> PmtreeResult<Self::Fr>;
 
     /// Outputs the default leaf (Fr::default())
     fn default_leaf() -> Self::Fr {
@@ -19,5 +23,33 @@ pub trait Hasher {
     }
 
     /// Calculates hash-function
-    fn hash(input: &[Self::Fr]) -> Self::Fr;
+    fn hash(&self, input: &[Self::Fr]) -> Self::Fr;
+
+    /// Hashes a leaf before it's combined with its sibling (identity by default)
+    fn hash_leaf(&self, leaf: Self::Fr) -> Self::Fr {
+        leaf
+    }
+
+    /// Hashes the children of an internal node at the specified level (root is level 0).
+    /// Calls `hash` by default
+    fn hash_node(&self, _level: usize, children: &[Self::Fr]) -> Self::Fr {
+        self.hash(children)
+    }
+
+    /// Outputs the default (zero) value of every level for a tree of the specified depth,
+    /// from the root (index 0) to the leaves (index `depth`).
+    /// Derived from `default_leaf` by default, can be overridden with precomputed values
+    fn zero_values(&self, depth: usize) -> Vec<Self::Fr> {
+        let mut zeros = vec![Self::default_leaf(); depth + 1];
+        for level in (0..depth).rev() {
+            let child = if level + 1 == depth {
+                self.hash_leaf(zeros[level + 1])
+            } else {
+                zeros[level + 1]
+            };
+            zeros[level] = self.hash_node(level, &[child, child]);
+        }
+
+        zeros
+    }
 }
@@ -0,0 +1,94 @@
+use crate::*;
+
+use std::fmt::Display;
+
+/// Version of the on-disk format, bumped between releases on every incompatible change
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Header describing how the stored tree was produced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeHeader {
+    /// Format version
+    pub version: u32,
+    /// Identifier of the hasher
+    pub hasher_id: String,
+    /// Byte length of a serialized leaf
+    pub leaf_len: usize,
+    /// Depth of the tree
+    pub depth: usize,
+}
+
+impl TreeHeader {
+    /// Creates header of the current format version for the specified hasher and depth
+    pub fn new<H: Hasher>(depth: usize) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            hasher_id: H::ID.to_string(),
+            leaf_len: H::serialize(H::default_leaf()).len(),
+            depth,
+        }
+    }
+
+    /// Serializes the header:
+    /// version (4 bytes) | depth (8 bytes) | leaf_len (8 bytes) | hasher_id (rest)
+    pub fn to_bytes(&self) -> Value {
+        let mut bytes = Vec::with_capacity(20 + self.hasher_id.len());
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        bytes.extend_from_slice(&(self.depth as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.leaf_len as u64).to_be_bytes());
+        bytes.extend_from_slice(self.hasher_id.as_bytes());
+
+        bytes
+    }
+
+    /// Deserializes the header
+    pub fn from_bytes(bytes: &[u8]) -> PmtreeResult<Self> {
+        let malformed =
+            || PmtreeErrorKind::DatabaseError(DatabaseErrorKind::MalformedMetadata("header"));
+
+        if bytes.len() < 20 {
+            return Err(malformed());
+        }
+
+        let version = u32::from_be_bytes(bytes[0..4].try_into().unwrap());
+        let depth = u64::from_be_bytes(bytes[4..12].try_into().unwrap());
+        let leaf_len = u64::from_be_bytes(bytes[12..20].try_into().unwrap());
+        let hasher_id = String::from_utf8(bytes[20..].to_vec()).map_err(|_| malformed())?;
+
+        Ok(Self {
+            version,
+            hasher_id,
+            leaf_len: leaf_len.try_into().map_err(|_| malformed())?,
+            depth: depth.try_into().map_err(|_| malformed())?,
+        })
+    }
+
+    /// Checks that a tree with this header can be opened as the expected one
+    pub fn check_compatible(&self, expected: &TreeHeader) -> PmtreeResult<()> {
+        let incompatible = |field: &str, expected: &dyn Display, actual: &dyn Display| {
+            Err(PmtreeErrorKind::DatabaseError(
+                DatabaseErrorKind::IncompatibleHeader(format!(
+                    "{field}: expected {expected}, found {actual}"
+                )),
+            ))
+        };
+
+        if self.version != expected.version {
+            return incompatible("format version", &expected.version, &self.version);
+        }
+
+        if self.hasher_id != expected.hasher_id {
+            return incompatible("hasher id", &expected.hasher_id, &self.hasher_id);
+        }
+
+        if self.leaf_len != expected.leaf_len {
+            return incompatible("leaf length", &expected.leaf_len, &self.leaf_len);
+        }
+
+        if self.depth != expected.depth {
+            return incompatible("depth", &expected.depth, &self.depth);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+use crate::state::Writes;
+use crate::tree::{db_key, TreeId};
+use crate::*;
+
+use std::collections::HashMap;
+
+// Tag of the leaf index buckets, db[tree_id | LEAF_INDEX_TAG | hash of the leaf] = bucket
+const LEAF_INDEX_TAG: u8 = u8::MAX - 1;
+
+// Entries (serialized leaf, index) of a bucket, ordered by index
+pub(crate) type Bucket = Vec<(Value, usize)>;
+
+// FNV-1a hash of the serialized leaf, truncated to the 7-byte key payload
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let hash = bytes.iter().fold(0xcbf29ce484222325u64, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+
+    hash & ((1 << 56) - 1)
+}
+
+// Returns db key of the bucket holding the serialized leaf
+pub(crate) fn bucket_key(tree_id: TreeId, leaf: &[u8]) -> DBKey {
+    db_key(tree_id, LEAF_INDEX_TAG, fnv1a(leaf))
+}
+
+// Decodes the bucket: every entry is index (8 bytes) | leaf length (4 bytes) | leaf
+pub(crate) fn decode_bucket(value: Option<Value>) -> PmtreeResult<Bucket> {
+    let malformed =
+        || PmtreeErrorKind::DatabaseError(DatabaseErrorKind::MalformedMetadata("leaf index"));
+
+    let mut bucket = Vec::new();
+    let Some(value) = value else {
+        return Ok(bucket);
+    };
+
+    let mut rest = value.as_slice();
+    while !rest.is_empty() {
+        if rest.len() < 12 {
+            return Err(malformed());
+        }
+
+        let index = u64::from_be_bytes(rest[0..8].try_into().unwrap());
+        let len = u32::from_be_bytes(rest[8..12].try_into().unwrap()) as usize;
+        let leaf = rest.get(12..12 + len).ok_or_else(malformed)?;
+
+        bucket.push((leaf.to_vec(), index.try_into().map_err(|_| malformed())?));
+        rest = &rest[12 + len..];
+    }
+
+    Ok(bucket)
+}
+
+// Encodes the bucket
+fn encode_bucket(bucket: &Bucket) -> Value {
+    let mut bytes = Vec::new();
+    for (leaf, index) in bucket {
+        bytes.extend_from_slice(&(*index as u64).to_be_bytes());
+        bytes.extend_from_slice(&(leaf.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(leaf);
+    }
+
+    bytes
+}
+
+// Changes of the leaf index caused by a tree update
+pub(crate) struct LeafIndexUpdate {
+    tree_id: TreeId,
+    removed: Vec<(Value, usize)>,
+    added: Vec<(Value, usize)>,
+}
+
+impl LeafIndexUpdate {
+    pub(crate) fn new(tree_id: TreeId) -> Self {
+        Self {
+            tree_id,
+            removed: Vec::new(),
+            added: Vec::new(),
+        }
+    }
+
+    // Records that the serialized leaf is removed from the index
+    pub(crate) fn remove(&mut self, leaf: Value, index: usize) {
+        self.removed.push((leaf, index));
+    }
+
+    // Records that the serialized leaf is added at the index
+    pub(crate) fn add(&mut self, leaf: Value, index: usize) {
+        self.added.push((leaf, index));
+    }
+
+    // Returns keys of the buckets touched by the update, without duplicates
+    pub(crate) fn bucket_keys(&self) -> Vec<DBKey> {
+        let mut keys: Vec<DBKey> = self
+            .removed
+            .iter()
+            .chain(&self.added)
+            .map(|(leaf, _)| bucket_key(self.tree_id, leaf))
+            .collect();
+        keys.sort_unstable();
+        keys.dedup();
+
+        keys
+    }
+
+    // Applies the update to the stored buckets read by `bucket_keys`.
+    // Returns the writes of the changed buckets, the emptied ones are deleted
+    pub(crate) fn apply(
+        self,
+        keys: Vec<DBKey>,
+        values: Vec<Option<Value>>,
+    ) -> PmtreeResult<Writes> {
+        let mut buckets = keys
+            .into_iter()
+            .zip(values)
+            .map(|(key, value)| Ok((key, decode_bucket(value)?)))
+            .collect::<PmtreeResult<HashMap<_, _>>>()?;
+
+        for (leaf, index) in self.removed {
+            if let Some(bucket) = buckets.get_mut(&bucket_key(self.tree_id, &leaf)) {
+                bucket.retain(|entry| entry.1 != index || entry.0 != leaf);
+            }
+        }
+
+        for (leaf, index) in self.added {
+            if let Some(bucket) = buckets.get_mut(&bucket_key(self.tree_id, &leaf)) {
+                bucket.push((leaf, index));
+            }
+        }
+
+        let mut writes = Writes::default();
+        for (key, mut bucket) in buckets {
+            if bucket.is_empty() {
+                writes.delete(key);
+            } else {
+                bucket.sort_unstable_by_key(|entry| entry.1);
+                writes.put(key, encode_bucket(&bucket));
+            }
+        }
+
+        Ok(writes)
+    }
+}
@@ -2,32 +2,89 @@
 //! Persistent Merkle Tree in Rust
 //!
 //! ## How it stored
-//! { (usize::MAX - 1) : depth }
-//! { (usize::MAX)     : next_index}
-//! { Position (tuple - (depth, index), converted to DBKey) : Value}
+//! Every key is prefixed with the 8-byte tree id, so several trees can share one db
+//! { tree_id | 0xFF | 0 : header (format version, hasher id, leaf length, depth) }
+//! { tree_id | 0xFF | 1 : depth }
+//! { tree_id | 0xFF | 2 : next_index }
+//! { tree_id | 0xFF | 3 : present if the leaf index is enabled }
+//! { tree_id | 0xFF | 4 : freed index ranges, present if the free list is enabled }
+//! { tree_id | 0xFF | 5 : number of non-default leaves }
+//! { tree_id | 0xFE | hash of the leaf (7 bytes) : (leaf, index) pairs }
+//! { tree_id | depth | index (7 bytes) : Value }
+//! { u64::MAX | tree_id : present for every tree in the db }
+//!
+//! Nodes equal to the default value of their level are not stored
+//!
+//! ## Compatibility
+//! Keys of 2.x databases are 8 bytes long, so they aren't readable by 3.x: loading them fails
+//! with `DatabaseErrorKind::MissingMetadata("depth")`. Trees are migrated by reinserting
+//! their leaves, see README
 
+#[cfg(feature = "async")]
+pub mod async_database;
+#[cfg(feature = "async")]
+pub mod async_tree;
+pub mod cache;
+pub mod circom;
 pub mod database;
+mod free_list;
 pub mod hasher;
+pub mod header;
+mod leaf_index;
+mod proof;
+pub mod shared;
+pub mod snapshot;
+mod state;
 pub mod tree;
 
 use std::fmt::{Debug, Display};
 
+#[cfg(feature = "async")]
+pub use async_database::*;
+#[cfg(feature = "async")]
+pub use async_tree::AsyncMerkleTree;
+pub use cache::CacheStats;
+pub use circom::CircomInput;
 pub use database::*;
 pub use hasher::*;
-pub use tree::MerkleTree;
+pub use header::TreeHeader;
+pub use shared::SharedMerkleTree;
+pub use snapshot::SnapshotHeader;
+pub use tree::{drop_tree, list_trees, Key, MerkleProof, MerkleTree, TreeId};
 
 /// Denotes keys in a database
-pub type DBKey = [u8; 8];
+pub type DBKey = [u8; 16];
 
 /// Denotes values in a database
 pub type Value = Vec<u8>;
 
+/// Denotes ordered iterator over database entries
+pub type DBIterator<'a> = Box<dyn Iterator<Item = PmtreeResult<(DBKey, Value)>> + 'a>;
+
 /// Denotes pmtree Merkle tree errors
 #[derive(Debug)]
 pub enum TreeErrorKind {
     MerkleTreeIsFull,
     InvalidKey,
     IndexOutOfBounds,
+    InvalidZeroValues,
+    /// Stored node can't be deserialized
+    CorruptedNode(tree::Key),
+    /// Loaded tree depth differs from the expected one
+    DepthMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    /// Depth exceeds `tree::MAX_DEPTH`
+    DepthTooLarge,
+    /// Tree id is reserved for the registry of trees
+    ReservedTreeId,
+    /// Thread pool for batch operations can't be built
+    ThreadPoolError(String),
+    /// Leaf index is required but not enabled for the tree
+    LeafIndexDisabled,
+    /// Encoded proof can't be decoded
+    MalformedProof(&'static str),
     CustomError(String),
 }
 
@@ -36,9 +93,36 @@ pub enum TreeErrorKind {
 pub enum DatabaseErrorKind {
     CannotLoadDatabase,
     DatabaseExists,
+    /// Tree with the id already exists in the db
+    TreeExists(tree::TreeId),
+    /// Metadata record (e.g. depth, next_index) is absent
+    MissingMetadata(&'static str),
+    /// Metadata record can't be decoded
+    MalformedMetadata(&'static str),
+    /// Operation isn't supported by the database
+    UnsupportedOperation(&'static str),
+    /// Stored tree header doesn't match the opening tree
+    IncompatibleHeader(String),
     CustomError(String),
 }
 
+/// Denotes pmtree snapshot errors
+#[derive(Debug)]
+pub enum SnapshotErrorKind {
+    /// Snapshot can't be read or written
+    Io(std::io::Error),
+    /// Snapshot doesn't start with the magic bytes
+    InvalidMagic,
+    /// Snapshot format version isn't supported
+    UnsupportedVersion(u32),
+    /// Snapshot was produced with another hasher
+    HasherMismatch { expected: String, actual: String },
+    /// Snapshot field can't be decoded
+    Malformed(&'static str),
+    /// Root of the imported tree differs from the snapshot one
+    RootMismatch,
+}
+
 /// Denotes pmtree errors
 #[derive(Debug)]
 pub enum PmtreeErrorKind {
@@ -46,6 +130,8 @@ pub enum PmtreeErrorKind {
     DatabaseError(DatabaseErrorKind),
     /// Error in tree
     TreeError(TreeErrorKind),
+    /// Error in snapshot export or import
+    SnapshotError(SnapshotErrorKind),
     /// Custom error
     CustomError(String),
 }
@@ -55,6 +141,7 @@ impl Display for PmtreeErrorKind {
         match self {
             PmtreeErrorKind::DatabaseError(e) => write!(f, "Database error: {e:?}"),
             PmtreeErrorKind::TreeError(e) => write!(f, "Tree error: {e:?}"),
+            PmtreeErrorKind::SnapshotError(e) => write!(f, "Snapshot error: {e:?}"),
             PmtreeErrorKind::CustomError(e) => write!(f, "Custom error: {e:?}"),
         }
     }
@@ -0,0 +1,174 @@
+use crate::tree::{MerkleProof, MAX_DEPTH};
+use crate::*;
+
+use std::fmt::{Debug, Formatter};
+
+impl<H: Hasher> MerkleProof<H> {
+    /// Encodes the proof in the canonical byte format:
+    /// depth (4 bytes) | element length (4 bytes) | (path index (1 byte) | element) per level,
+    /// from the leaf level to the root. Elements are serialized with `H::serialize`
+    pub fn to_bytes(&self) -> Value {
+        let elements: Vec<Value> = self.0.iter().map(|&(e, _)| H::serialize(e)).collect();
+        let element_len = elements.first().map_or(0, Vec::len);
+
+        let mut bytes = Vec::with_capacity(8 + elements.len() * (1 + element_len));
+        bytes.extend_from_slice(&(self.0.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&(element_len as u32).to_be_bytes());
+        for (element, &(_, index)) in elements.iter().zip(&self.0) {
+            bytes.push(index);
+            bytes.extend_from_slice(element);
+        }
+
+        bytes
+    }
+
+    /// Decodes the proof from the canonical byte format, validating its length,
+    /// path indexes and elements
+    pub fn from_bytes(bytes: &[u8]) -> PmtreeResult<Self> {
+        if bytes.len() < 8 {
+            return Err(malformed("length"));
+        }
+
+        let depth = u32::from_be_bytes(bytes[0..4].try_into().unwrap()) as usize;
+        let element_len = u32::from_be_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        if depth > MAX_DEPTH {
+            return Err(PmtreeErrorKind::TreeError(TreeErrorKind::DepthTooLarge));
+        }
+
+        if (bytes.len() - 8) != depth * (1 + element_len) {
+            return Err(malformed("length"));
+        }
+
+        let witness = bytes[8..]
+            .chunks(1 + element_len)
+            .map(|chunk| {
+                let index = chunk[0];
+                if index > 1 {
+                    return Err(malformed("path index"));
+                }
+
+                let element =
+                    H::deserialize(chunk[1..].to_vec()).map_err(|_| malformed("element"))?;
+                if H::serialize(element).len() != element_len {
+                    return Err(malformed("element"));
+                }
+
+                Ok((element, index))
+            })
+            .collect::<PmtreeResult<_>>()?;
+
+        Ok(MerkleProof(witness))
+    }
+
+    /// Decodes the proof from the canonical byte format,
+    /// failing if it's not a proof for a tree of the expected depth
+    pub fn from_bytes_with_depth(bytes: &[u8], depth: usize) -> PmtreeResult<Self> {
+        let proof = Self::from_bytes(bytes)?;
+        proof.check_depth(depth)?;
+
+        Ok(proof)
+    }
+}
+
+fn malformed(field: &'static str) -> PmtreeErrorKind {
+    PmtreeErrorKind::TreeError(TreeErrorKind::MalformedProof(field))
+}
+
+// Implemented manually, so that the hasher itself isn't required to implement the traits
+impl<H: Hasher> Clone for MerkleProof<H> {
+    fn clone(&self) -> Self {
+        MerkleProof(self.0.clone())
+    }
+}
+
+impl<H: Hasher> PartialEq for MerkleProof<H> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<H: Hasher> Eq for MerkleProof<H> {}
+
+impl<H: Hasher> Debug for MerkleProof<H> {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        f.debug_struct("MerkleProof")
+            .field("path_elements", &self.get_path_elements())
+            .field("path_index", &self.get_path_index())
+            .finish()
+    }
+}
+
+/// Proofs are serialized as their canonical bytes: a hex string for human-readable formats
+/// (e.g. JSON), raw bytes otherwise
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use super::*;
+
+    use serde::de::{Error, SeqAccess, Visitor};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    impl<H: Hasher> Serialize for MerkleProof<H> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let bytes = self.to_bytes();
+            if serializer.is_human_readable() {
+                let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+                serializer.serialize_str(&format!("0x{hex}"))
+            } else {
+                serializer.serialize_bytes(&bytes)
+            }
+        }
+    }
+
+    impl<'de, H: Hasher> Deserialize<'de> for MerkleProof<H> {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let bytes = if deserializer.is_human_readable() {
+                deserializer.deserialize_str(BytesVisitor)?
+            } else {
+                deserializer.deserialize_byte_buf(BytesVisitor)?
+            };
+
+            MerkleProof::from_bytes(&bytes).map_err(D::Error::custom)
+        }
+    }
+
+    // Accepts a hex string (with optional 0x prefix) or bytes
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Value;
+
+        fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+            f.write_str("merkle proof bytes or hex string")
+        }
+
+        fn visit_str<E: Error>(self, hex: &str) -> Result<Value, E> {
+            let hex = hex.strip_prefix("0x").unwrap_or(hex);
+            if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+                return Err(E::custom("invalid hex string"));
+            }
+
+            (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<Result<_, _>>()
+                .map_err(|_| E::custom("invalid hex string"))
+        }
+
+        fn visit_bytes<E: Error>(self, bytes: &[u8]) -> Result<Value, E> {
+            Ok(bytes.to_vec())
+        }
+
+        fn visit_byte_buf<E: Error>(self, bytes: Value) -> Result<Value, E> {
+            Ok(bytes)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+
+            Ok(bytes)
+        }
+    }
+}
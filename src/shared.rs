@@ -0,0 +1,460 @@
+use crate::state::check_batch;
+use crate::tree::*;
+use crate::*;
+
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{
+    Arc, Mutex, MutexGuard, OnceLock, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+
+/// Merkle Tree shared between one writer and many concurrent readers.
+///
+/// Readers take snapshots of the last published version of the tree: the root, next_index
+/// and all the nodes stay as they were at the publication, however long the snapshot lives,
+/// so `proof`/`get`/`verify` never observe a half-applied update.
+/// Taking and reading a snapshot never waits for the writer, only for the single db calls.
+/// The writer applies its changes exclusively and publishes them with `TreeWriter::commit`
+/// or when the write handle is dropped
+pub struct SharedMerkleTree<D, H>
+where
+    D: Database,
+    H: Hasher,
+{
+    inner: Arc<Inner<D, H>>,
+}
+
+struct Inner<D, H>
+where
+    D: Database,
+    H: Hasher,
+{
+    writer: Mutex<MerkleTree<VersionedDb<D>, H>>,
+    store: Arc<Store<D>>,
+    published: Mutex<Arc<Version<H::Fr>>>,
+    tree_id: TreeId,
+    depth: usize,
+    cache: Vec<H::Fr>,
+    hasher: Arc<H>,
+}
+
+// Db shared by the writer and the readers, with the overlay recording the writes
+// made after the last publication
+struct Store<D> {
+    db: RwLock<D>,
+    overlay: Mutex<Arc<Overlay>>,
+}
+
+// Values the keys held before they were first overwritten after a publication.
+// `next` links to the overlay of the following publication
+#[derive(Default)]
+struct Overlay {
+    overwritten: RwLock<HashMap<DBKey, Option<Value>>>,
+    next: OnceLock<Arc<Overlay>>,
+}
+
+// Published version of the tree
+struct Version<F> {
+    root: F,
+    next_index: usize,
+    overlay: Arc<Overlay>,
+}
+
+impl<D> Store<D> {
+    fn db(&self) -> PmtreeResult<RwLockReadGuard<'_, D>> {
+        self.db.read().map_err(|_| poisoned())
+    }
+
+    fn db_mut(&self) -> PmtreeResult<RwLockWriteGuard<'_, D>> {
+        self.db.write().map_err(|_| poisoned())
+    }
+
+    fn overlay(&self) -> MutexGuard<'_, Arc<Overlay>> {
+        // The pointer is only swapped, it's never left half-updated
+        self.overlay.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn poisoned() -> PmtreeErrorKind {
+    PmtreeErrorKind::DatabaseError(DatabaseErrorKind::CustomError(String::from(
+        "Shared db lock is poisoned",
+    )))
+}
+
+/// Db of the shared tree writer: before a key is first overwritten after a publication,
+/// its value is kept for the snapshots taken before
+pub struct VersionedDb<D>(Arc<Store<D>>);
+
+impl<D: Database> VersionedDb<D> {
+    fn from_db(db: D) -> Self {
+        Self(Arc::new(Store {
+            db: RwLock::new(db),
+            overlay: Mutex::new(Arc::default()),
+        }))
+    }
+
+    // Keeps the current values of the keys not yet overwritten since the last publication
+    fn record(&self, keys: impl IntoIterator<Item = DBKey>) -> PmtreeResult<()> {
+        let overlay = Arc::clone(&self.0.overlay());
+
+        let keys: Vec<DBKey> = {
+            let overwritten = overlay
+                .overwritten
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            keys.into_iter()
+                .filter(|key| !overwritten.contains_key(key))
+                .collect()
+        };
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let values = check_batch(keys.len(), self.0.db()?.get_batch(&keys)?)?;
+        let mut overwritten = overlay
+            .overwritten
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        for (key, value) in keys.into_iter().zip(values) {
+            overwritten.entry(key).or_insert(value);
+        }
+
+        Ok(())
+    }
+}
+
+impl<D: Database> Database for VersionedDb<D> {
+    type Config = D::Config;
+
+    fn new(config: Self::Config) -> PmtreeResult<Self> {
+        Ok(Self::from_db(D::new(config)?))
+    }
+
+    fn load(config: Self::Config) -> PmtreeResult<Self> {
+        Ok(Self::from_db(D::load(config)?))
+    }
+
+    fn get(&self, key: DBKey) -> PmtreeResult<Option<Value>> {
+        self.0.db()?.get(key)
+    }
+
+    fn get_batch(&self, keys: &[DBKey]) -> PmtreeResult<Vec<Option<Value>>> {
+        self.0.db()?.get_batch(keys)
+    }
+
+    fn put(&mut self, key: DBKey, value: Value) -> PmtreeResult<()> {
+        self.record([key])?;
+        self.0.db_mut()?.put(key, value)
+    }
+
+    fn put_batch(&mut self, subtree: HashMap<DBKey, Value>) -> PmtreeResult<()> {
+        self.record(subtree.keys().copied())?;
+        self.0.db_mut()?.put_batch(subtree)
+    }
+
+    fn delete(&mut self, key: DBKey) -> PmtreeResult<()> {
+        self.record([key])?;
+        self.0.db_mut()?.delete(key)
+    }
+
+    fn delete_batch(&mut self, keys: Vec<DBKey>) -> PmtreeResult<()> {
+        self.record(keys.iter().copied())?;
+        self.0.db_mut()?.delete_batch(keys)
+    }
+
+    // Entries are collected while the lock is held
+    fn iter_range(&self, from: DBKey, to: DBKey) -> PmtreeResult<DBIterator<'_>> {
+        let entries = self
+            .0
+            .db()?
+            .iter_range(from, to)?
+            .collect::<PmtreeResult<Vec<_>>>()?;
+
+        Ok(Box::new(entries.into_iter().map(Ok)))
+    }
+
+    fn close(&mut self) -> PmtreeResult<()> {
+        self.0.db_mut()?.close()
+    }
+}
+
+impl<D, H> Clone for SharedMerkleTree<D, H>
+where
+    D: Database,
+    H: Hasher,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<D, H> SharedMerkleTree<D, H>
+where
+    D: Database,
+    H: Hasher,
+{
+    /// Wraps the tree to be shared between threads
+    pub fn new(tree: MerkleTree<D, H>) -> Self {
+        let tree = tree.map_db(VersionedDb::from_db);
+        let store = Arc::clone(&tree.db().0);
+
+        let state = tree.state();
+        let version = Version {
+            root: state.root,
+            next_index: state.next_index,
+            overlay: Arc::clone(&store.overlay()),
+        };
+
+        Self {
+            inner: Arc::new(Inner {
+                tree_id: state.tree_id,
+                depth: state.depth,
+                cache: state.cache.clone(),
+                hasher: Arc::clone(&state.hasher),
+                writer: Mutex::new(tree),
+                store,
+                published: Mutex::new(Arc::new(version)),
+            }),
+        }
+    }
+
+    // Returns the last published version
+    fn published(&self) -> Arc<Version<H::Fr>> {
+        // The pointer is only swapped, it's never left half-updated
+        Arc::clone(
+            &self
+                .inner
+                .published
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        )
+    }
+
+    /// Returns the last published root without waiting for the writer
+    pub fn root(&self) -> H::Fr {
+        self.published().root
+    }
+
+    /// Takes a read-only snapshot of the last published version without waiting for the writer
+    pub fn snapshot(&self) -> TreeSnapshot<D, H> {
+        TreeSnapshot {
+            inner: Arc::clone(&self.inner),
+            version: self.published(),
+        }
+    }
+
+    /// Takes the exclusive write handle, waiting for the other writer.
+    /// Fails if the other writer panicked, as the tree may hold a half-applied update
+    pub fn write(&self) -> PmtreeResult<TreeWriter<'_, D, H>> {
+        let tree = self.inner.writer.lock().map_err(|_| {
+            PmtreeErrorKind::TreeError(TreeErrorKind::CustomError(String::from(
+                "Shared tree writer panicked",
+            )))
+        })?;
+
+        Ok(TreeWriter {
+            tree,
+            inner: &self.inner,
+        })
+    }
+
+    /// Returns the tree if this is the last handle to it and no snapshots are alive,
+    /// gives the handle back otherwise
+    pub fn into_inner(self) -> Result<MerkleTree<D, H>, Self> {
+        let Inner { writer, store, .. } =
+            Arc::try_unwrap(self.inner).map_err(|inner| Self { inner })?;
+        drop(store);
+
+        // A panicked writer leaves the tree as is, `verify_integrity` can check it
+        let tree = writer.into_inner().unwrap_or_else(PoisonError::into_inner);
+
+        // With the snapshots gone, the store is referenced only by the tree
+        Ok(tree.map_db(|db| {
+            let store = Arc::into_inner(db.0).expect("store is referenced only by the tree");
+            store
+                .db
+                .into_inner()
+                .unwrap_or_else(PoisonError::into_inner)
+        }))
+    }
+}
+
+/// Read-only view of a published version of the shared tree
+pub struct TreeSnapshot<D, H>
+where
+    D: Database,
+    H: Hasher,
+{
+    inner: Arc<Inner<D, H>>,
+    version: Arc<Version<H::Fr>>,
+}
+
+impl<D, H> TreeSnapshot<D, H>
+where
+    D: Database,
+    H: Hasher,
+{
+    /// Returns the root of the version
+    pub fn root(&self) -> H::Fr {
+        self.version.root
+    }
+
+    /// Returns next_index of the version
+    pub fn leaves_set(&self) -> usize {
+        self.version.next_index
+    }
+
+    /// Returns the depth of the tree
+    pub fn depth(&self) -> usize {
+        self.inner.depth
+    }
+
+    /// Returns the leaf by the key
+    pub fn get(&self, key: usize) -> PmtreeResult<H::Fr> {
+        if key >= 1 << self.inner.depth {
+            return Err(PmtreeErrorKind::TreeError(TreeErrorKind::IndexOutOfBounds));
+        }
+
+        Ok(self.read(&[Key(self.inner.depth, key)])?[0])
+    }
+
+    /// Computes a Merkle proof for the leaf at the specified index
+    pub fn proof(&self, index: usize) -> PmtreeResult<MerkleProof<H>> {
+        if index >= 1 << self.inner.depth {
+            return Err(PmtreeErrorKind::TreeError(TreeErrorKind::IndexOutOfBounds));
+        }
+
+        let keys = path_siblings(self.inner.depth, index);
+        let siblings = self.read(&keys)?;
+
+        Ok(build_proof(
+            &*self.inner.hasher,
+            self.inner.depth,
+            &keys,
+            siblings,
+        ))
+    }
+
+    /// Verifies a Merkle proof with respect to the input leaf and the root of the version,
+    /// rejecting proofs whose length differs from the tree depth
+    pub fn verify(&self, leaf: &H::Fr, witness: &MerkleProof<H>) -> bool {
+        witness.length() == self.inner.depth
+            && witness.verify_with_hasher(&self.inner.hasher, leaf, &self.version.root)
+    }
+
+    // Reads the nodes as of the version. The db is read first: a key overwritten
+    // after that read has its previous value recorded in the overlays by then
+    fn read(&self, keys: &[Key]) -> PmtreeResult<Vec<H::Fr>> {
+        let db_keys: Vec<DBKey> = keys
+            .iter()
+            .map(|key| key.to_db_key(self.inner.tree_id))
+            .collect();
+        let mut values = check_batch(keys.len(), self.inner.store.db()?.get_batch(&db_keys)?)?;
+
+        // The first overlay holding the key has its value as of the version
+        let mut pending: Vec<usize> = (0..keys.len()).collect();
+        let mut overlay = Some(&self.version.overlay);
+        while let Some(current) = overlay {
+            if pending.is_empty() {
+                break;
+            }
+
+            let overwritten = current
+                .overwritten
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            pending.retain(|&i| match overwritten.get(&db_keys[i]) {
+                Some(value) => {
+                    values[i] = value.clone();
+                    false
+                }
+                None => true,
+            });
+            overlay = current.next.get();
+        }
+
+        keys.iter()
+            .zip(values)
+            .map(|(&key, value)| match value {
+                Some(value) => deserialize_node::<H>(key, value),
+                None => Ok(self.inner.cache[key.0]),
+            })
+            .collect()
+    }
+}
+
+/// Exclusive write handle of the shared tree
+pub struct TreeWriter<'a, D, H>
+where
+    D: Database,
+    H: Hasher,
+{
+    tree: MutexGuard<'a, MerkleTree<VersionedDb<D>, H>>,
+    inner: &'a Inner<D, H>,
+}
+
+impl<D, H> TreeWriter<'_, D, H>
+where
+    D: Database,
+    H: Hasher,
+{
+    /// Publishes the updates applied so far: the snapshots taken from now on see them
+    pub fn commit(&mut self) {
+        let next = Arc::new(Overlay::default());
+
+        // Writes after this point are recorded for the version being published
+        let mut current = self.inner.store.overlay();
+        let _ = current.next.set(Arc::clone(&next));
+        *current = Arc::clone(&next);
+        drop(current);
+
+        let version = Version {
+            root: self.tree.root(),
+            next_index: self.tree.leaves_set(),
+            overlay: next,
+        };
+        *self
+            .inner
+            .published
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Arc::new(version);
+    }
+}
+
+impl<D, H> Deref for TreeWriter<'_, D, H>
+where
+    D: Database,
+    H: Hasher,
+{
+    type Target = MerkleTree<VersionedDb<D>, H>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tree
+    }
+}
+
+impl<D, H> DerefMut for TreeWriter<'_, D, H>
+where
+    D: Database,
+    H: Hasher,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.tree
+    }
+}
+
+impl<D, H> Drop for TreeWriter<'_, D, H>
+where
+    D: Database,
+    H: Hasher,
+{
+    // Publishes while the tree is still locked, so the version is never ahead of the nodes.
+    // Updates interrupted by a panic aren't published
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            self.commit();
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use crate::*;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+
+/// Version of the snapshot format, bumped on every incompatible change
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+// Bytes every snapshot starts with
+const MAGIC: &[u8; 4] = b"PMTS";
+
+// Maximal length of a length-prefixed field, guards allocations on malformed input
+const MAX_FIELD_LEN: usize = 1 << 20;
+
+/// Header of a portable tree snapshot, followed by `leaves` entries of
+/// index (8 bytes) | leaf length (4 bytes) | serialized leaf, ordered by index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotHeader {
+    /// Snapshot format version
+    pub version: u32,
+    /// Identifier of the hasher
+    pub hasher_id: String,
+    /// Depth of the tree
+    pub depth: usize,
+    /// Next index of the tree
+    pub next_index: usize,
+    /// Serialized root of the tree
+    pub root: Value,
+    /// Number of the leaf entries
+    pub leaves: usize,
+}
+
+impl SnapshotHeader {
+    /// Writes the header:
+    /// magic (4 bytes) | version (4 bytes) | hasher_id (length-prefixed) | depth (8 bytes) |
+    /// next_index (8 bytes) | root (length-prefixed) | leaves (8 bytes)
+    pub fn write<W: Write>(&self, writer: &mut W) -> PmtreeResult<()> {
+        write_all(writer, MAGIC)?;
+        write_all(writer, &self.version.to_be_bytes())?;
+        write_bytes(writer, self.hasher_id.as_bytes())?;
+        write_all(writer, &(self.depth as u64).to_be_bytes())?;
+        write_all(writer, &(self.next_index as u64).to_be_bytes())?;
+        write_bytes(writer, &self.root)?;
+        write_all(writer, &(self.leaves as u64).to_be_bytes())
+    }
+
+    /// Reads the header, checking the magic bytes and the version
+    pub fn read<R: Read>(reader: &mut R) -> PmtreeResult<Self> {
+        let mut magic = [0; 4];
+        read_exact(reader, &mut magic)?;
+        if &magic != MAGIC {
+            return Err(snapshot_error(SnapshotErrorKind::InvalidMagic));
+        }
+
+        let version = u32::from_be_bytes(read_array(reader)?);
+        if version != SNAPSHOT_VERSION {
+            return Err(snapshot_error(SnapshotErrorKind::UnsupportedVersion(
+                version,
+            )));
+        }
+
+        let hasher_id = String::from_utf8(read_bytes(reader)?)
+            .map_err(|_| snapshot_error(SnapshotErrorKind::Malformed("hasher id")))?;
+
+        Ok(Self {
+            version,
+            hasher_id,
+            depth: read_usize(reader, "depth")?,
+            next_index: read_usize(reader, "next_index")?,
+            root: read_bytes(reader)?,
+            leaves: read_usize(reader, "leaves")?,
+        })
+    }
+}
+
+// Writes the leaf entry
+pub(crate) fn write_leaf<W: Write>(writer: &mut W, index: usize, leaf: &[u8]) -> PmtreeResult<()> {
+    write_all(writer, &(index as u64).to_be_bytes())?;
+    write_bytes(writer, leaf)
+}
+
+// Reads the leaf entry
+pub(crate) fn read_leaf<R: Read>(reader: &mut R) -> PmtreeResult<(usize, Value)> {
+    Ok((read_usize(reader, "leaf index")?, read_bytes(reader)?))
+}
+
+// Db of the tree being imported, recording the written keys
+// so that a failed import removes everything it has stored
+pub(crate) struct ImportDb<D> {
+    pub(crate) db: D,
+    written: HashSet<DBKey>,
+}
+
+impl<D: Database> ImportDb<D> {
+    pub(crate) fn from_db(db: D) -> Self {
+        Self {
+            db,
+            written: HashSet::new(),
+        }
+    }
+
+    // Deletes all the keys written by the import
+    pub(crate) fn discard(&mut self) -> PmtreeResult<()> {
+        let keys = self.written.drain().collect();
+        self.db.delete_batch(keys)
+    }
+}
+
+impl<D: Database> Database for ImportDb<D> {
+    type Config = D::Config;
+
+    fn new(config: Self::Config) -> PmtreeResult<Self> {
+        Ok(Self::from_db(D::new(config)?))
+    }
+
+    fn load(config: Self::Config) -> PmtreeResult<Self> {
+        Ok(Self::from_db(D::load(config)?))
+    }
+
+    fn get(&self, key: DBKey) -> PmtreeResult<Option<Value>> {
+        self.db.get(key)
+    }
+
+    fn get_batch(&self, keys: &[DBKey]) -> PmtreeResult<Vec<Option<Value>>> {
+        self.db.get_batch(keys)
+    }
+
+    fn put(&mut self, key: DBKey, value: Value) -> PmtreeResult<()> {
+        self.written.insert(key);
+        self.db.put(key, value)
+    }
+
+    fn put_batch(&mut self, subtree: HashMap<DBKey, Value>) -> PmtreeResult<()> {
+        self.written.extend(subtree.keys());
+        self.db.put_batch(subtree)
+    }
+
+    fn delete(&mut self, key: DBKey) -> PmtreeResult<()> {
+        self.db.delete(key)
+    }
+
+    fn delete_batch(&mut self, keys: Vec<DBKey>) -> PmtreeResult<()> {
+        self.db.delete_batch(keys)
+    }
+
+    fn iter_range(&self, from: DBKey, to: DBKey) -> PmtreeResult<DBIterator<'_>> {
+        self.db.iter_range(from, to)
+    }
+
+    fn close(&mut self) -> PmtreeResult<()> {
+        self.db.close()
+    }
+}
+
+pub(crate) fn snapshot_error(kind: SnapshotErrorKind) -> PmtreeErrorKind {
+    PmtreeErrorKind::SnapshotError(kind)
+}
+
+fn write_all<W: Write>(writer: &mut W, bytes: &[u8]) -> PmtreeResult<()> {
+    writer
+        .write_all(bytes)
+        .map_err(|e| snapshot_error(SnapshotErrorKind::Io(e)))
+}
+
+// Writes bytes prefixed with their length (4 bytes)
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> PmtreeResult<()> {
+    write_all(writer, &(bytes.len() as u32).to_be_bytes())?;
+    write_all(writer, bytes)
+}
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> PmtreeResult<()> {
+    reader
+        .read_exact(buf)
+        .map_err(|e| snapshot_error(SnapshotErrorKind::Io(e)))
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> PmtreeResult<[u8; N]> {
+    let mut bytes = [0; N];
+    read_exact(reader, &mut bytes)?;
+
+    Ok(bytes)
+}
+
+fn read_usize<R: Read>(reader: &mut R, name: &'static str) -> PmtreeResult<usize> {
+    u64::from_be_bytes(read_array(reader)?)
+        .try_into()
+        .map_err(|_| snapshot_error(SnapshotErrorKind::Malformed(name)))
+}
+
+// Reads bytes prefixed with their length (4 bytes)
+fn read_bytes<R: Read>(reader: &mut R) -> PmtreeResult<Value> {
+    let len = u32::from_be_bytes(read_array(reader)?) as usize;
+    if len > MAX_FIELD_LEN {
+        return Err(snapshot_error(SnapshotErrorKind::Malformed("field length")));
+    }
+
+    let mut bytes = vec![0; len];
+    read_exact(reader, &mut bytes)?;
+
+    Ok(bytes)
+}
@@ -0,0 +1,611 @@
+use crate::cache::NodeCache;
+use crate::free_list::FreeList;
+use crate::leaf_index::{decode_bucket, LeafIndexUpdate};
+use crate::tree::*;
+use crate::*;
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+// Db writes of a tree update, applied as one put batch and one delete batch
+#[derive(Default)]
+pub(crate) struct Writes {
+    pub(crate) puts: HashMap<DBKey, Value>,
+    pub(crate) deletes: Vec<DBKey>,
+}
+
+impl Writes {
+    pub(crate) fn put(&mut self, key: DBKey, value: Value) {
+        self.puts.insert(key, value);
+    }
+
+    pub(crate) fn delete(&mut self, key: DBKey) {
+        self.deletes.push(key);
+    }
+
+    pub(crate) fn extend(&mut self, other: Writes) {
+        self.puts.extend(other.puts);
+        self.deletes.extend(other.deletes);
+    }
+}
+
+// Update of the tree: its db writes and the changes of the in-memory state,
+// applied by `TreeState::apply` only once the writes are stored
+pub(crate) struct Update<H: Hasher> {
+    pub(crate) writes: Writes,
+    root: Option<H::Fr>,
+    nodes: Nodes<H>,
+    next_index: Option<usize>,
+    occupied: Option<usize>,
+    free_list: Option<FreeList>,
+    leaf_index: bool,
+}
+
+impl<H: Hasher> Default for Update<H> {
+    fn default() -> Self {
+        Self {
+            writes: Writes::default(),
+            root: None,
+            nodes: Vec::new(),
+            next_index: None,
+            occupied: None,
+            free_list: None,
+            leaf_index: false,
+        }
+    }
+}
+
+// Checks that the db returned a value for every requested key
+pub(crate) fn check_batch(
+    len: usize,
+    values: Vec<Option<Value>>,
+) -> PmtreeResult<Vec<Option<Value>>> {
+    if values.len() != len {
+        return Err(PmtreeErrorKind::DatabaseError(
+            DatabaseErrorKind::CustomError(String::from(
+                "get_batch returned wrong number of values",
+            )),
+        ));
+    }
+
+    Ok(values)
+}
+
+// In-memory state of the tree and the update logic shared by `MerkleTree` and `AsyncMerkleTree`.
+// It never touches the db: the keys to read are returned to the tree,
+// and the updates are returned as `Writes`
+pub(crate) struct TreeState<H: Hasher> {
+    pub(crate) tree_id: TreeId,
+    pub(crate) depth: usize,
+    pub(crate) next_index: usize,
+    pub(crate) cache: Vec<H::Fr>,
+    pub(crate) root: H::Fr,
+    pub(crate) hasher: Arc<H>,
+    pub(crate) thread_pool: Option<Arc<rayon::ThreadPool>>,
+    pub(crate) parallel_threshold: usize,
+    pub(crate) node_cache: Option<Mutex<NodeCache<H::Fr>>>,
+    pub(crate) leaf_index: bool,
+    pub(crate) free_list: Option<FreeList>,
+    pub(crate) occupied: usize,
+}
+
+impl<H: Hasher> TreeState<H> {
+    fn new(tree_id: TreeId, depth: usize, next_index: usize, hasher: H) -> PmtreeResult<Self> {
+        // Cache nodes, default nodes are never stored
+        let cache = default_nodes(&hasher, depth)?;
+
+        Ok(Self {
+            tree_id,
+            depth,
+            next_index,
+            root: cache[0],
+            cache,
+            hasher: Arc::new(hasher),
+            thread_pool: None,
+            parallel_threshold: DEFAULT_PARALLEL_THRESHOLD,
+            node_cache: None,
+            leaf_index: false,
+            free_list: None,
+            occupied: 0,
+        })
+    }
+
+    // Keys read by `create`: the depth record under the new id
+    pub(crate) fn create_keys(tree_id: TreeId) -> Vec<DBKey> {
+        vec![metadata_key(tree_id, DEPTH)]
+    }
+
+    // Creates the state of a new tree from the values read by `create_keys`.
+    // Returns it with the writes storing the metadata and registering the tree
+    pub(crate) fn create(
+        tree_id: TreeId,
+        depth: usize,
+        hasher: H,
+        values: Vec<Option<Value>>,
+    ) -> PmtreeResult<(Self, Writes)> {
+        let [stored_depth] = <[_; 1]>::try_from(check_batch(1, values)?).unwrap();
+        check_new_tree(tree_id, depth, stored_depth)?;
+
+        let state = Self::new(tree_id, depth, 0, hasher)?;
+
+        let mut writes = Writes::default();
+        writes.put(
+            metadata_key(tree_id, HEADER),
+            TreeHeader::new::<H>(depth).to_bytes(),
+        );
+        writes.put(metadata_key(tree_id, DEPTH), depth.to_be_bytes().to_vec());
+        writes.extend(state.counters(0, 0));
+        writes.put(registry_key(tree_id), Vec::new());
+
+        Ok((state, writes))
+    }
+
+    // Keys read by `open`: the metadata records and the root
+    pub(crate) fn open_keys(tree_id: TreeId) -> Vec<DBKey> {
+        [DEPTH, HEADER, NEXT_INDEX, LEAF_INDEX, FREE_LIST, OCCUPIED]
+            .into_iter()
+            .map(|id| metadata_key(tree_id, id))
+            .chain([Key(0, 0).to_db_key(tree_id)])
+            .collect()
+    }
+
+    // Opens the state of the stored tree from the values read by `open_keys`, validating them
+    pub(crate) fn open(
+        tree_id: TreeId,
+        hasher: H,
+        expected_depth: Option<usize>,
+        values: Vec<Option<Value>>,
+    ) -> PmtreeResult<Self> {
+        let [depth, header, next_index, leaf_index, free_list, occupied, root] =
+            <[_; 7]>::try_from(check_batch(7, values)?).unwrap();
+
+        let (depth, next_index) = check_metadata::<H>(depth, header, next_index, expected_depth)?;
+
+        let free_list = free_list.map(FreeList::decode).transpose()?;
+        if free_list
+            .as_ref()
+            .is_some_and(|free_list| free_list.end() > next_index)
+        {
+            return Err(PmtreeErrorKind::DatabaseError(
+                DatabaseErrorKind::MalformedMetadata("free list"),
+            ));
+        }
+
+        let mut state = Self::new(tree_id, depth, next_index, hasher)?;
+        state.leaf_index = leaf_index.is_some();
+        state.free_list = free_list;
+
+        if let Some(root) = root {
+            state.root = deserialize_node::<H>(Key(0, 0), root)?;
+        }
+
+        state.occupied = decode_metadata(occupied, "occupied_count")?;
+
+        Ok(state)
+    }
+
+    // Returns the writes of next_index and the number of non-default leaves
+    fn counters(&self, next_index: usize, occupied: usize) -> Writes {
+        let mut writes = Writes::default();
+        writes.put(
+            metadata_key(self.tree_id, NEXT_INDEX),
+            next_index.to_be_bytes().to_vec(),
+        );
+        writes.put(
+            metadata_key(self.tree_id, OCCUPIED),
+            occupied.to_be_bytes().to_vec(),
+        );
+
+        writes
+    }
+
+    pub(crate) fn default_leaf(&self) -> &H::Fr {
+        &self.cache[self.depth]
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        1 << self.depth
+    }
+
+    // Returns keys of the leaves in the range
+    pub(crate) fn leaf_keys(&self, range: Range<usize>) -> Vec<Key> {
+        range.map(|i| Key(self.depth, i)).collect()
+    }
+
+    // Checks that the node at the level and the index is within the tree
+    pub(crate) fn check_node(&self, level: usize, index: usize) -> PmtreeResult<()> {
+        if level > self.depth || index >= (1 << level) {
+            return Err(PmtreeErrorKind::TreeError(TreeErrorKind::IndexOutOfBounds));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn db_keys(&self, keys: &[Key]) -> Vec<DBKey> {
+        keys.iter().map(|key| key.to_db_key(self.tree_id)).collect()
+    }
+
+    // Decodes the nodes read by the keys, absent ones hold the default value of their level
+    pub(crate) fn decode_nodes(
+        &self,
+        keys: &[Key],
+        values: Vec<Option<Value>>,
+    ) -> PmtreeResult<Vec<H::Fr>> {
+        keys.iter()
+            .zip(check_batch(keys.len(), values)?)
+            .map(|(&key, value)| match value {
+                Some(value) => deserialize_node::<H>(key, value),
+                None => Ok(self.cache[key.0]),
+            })
+            .collect()
+    }
+
+    fn lock_node_cache(&self) -> Option<MutexGuard<'_, NodeCache<H::Fr>>> {
+        // The cache is only a copy of the db, so it's still usable after a panic
+        self.node_cache
+            .as_ref()
+            .map(|node_cache| node_cache.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    // Looks the nodes up in the node cache.
+    // Returns the found ones and the keys of the missing ones, to be read from the db
+    pub(crate) fn cached_nodes(&self, keys: &[Key]) -> (Vec<Option<H::Fr>>, Vec<Key>) {
+        let cached: Vec<Option<H::Fr>> = match self.lock_node_cache() {
+            Some(mut node_cache) => keys.iter().map(|key| node_cache.get(key)).collect(),
+            None => vec![None; keys.len()],
+        };
+        let missing = keys
+            .iter()
+            .zip(&cached)
+            .filter(|(_, value)| value.is_none())
+            .map(|(&key, _)| key)
+            .collect();
+
+        (cached, missing)
+    }
+
+    // Completes the nodes found by `cached_nodes` with the ones read by the missing keys,
+    // putting the read ones to the node cache
+    pub(crate) fn fill_nodes(
+        &self,
+        cached: Vec<Option<H::Fr>>,
+        missing: &[Key],
+        read: Vec<H::Fr>,
+    ) -> Vec<H::Fr> {
+        if let Some(mut node_cache) = self.lock_node_cache() {
+            for (&key, &value) in missing.iter().zip(&read) {
+                node_cache.insert(key, value);
+            }
+        }
+
+        let mut read = read.into_iter();
+        cached
+            .into_iter()
+            .map(|value| value.or_else(|| read.next()).unwrap())
+            .collect()
+    }
+
+    // Returns the update storing the nodes and setting the root, the nodes go to the node cache
+    // once stored. Nodes equal to the level default are deleted from the db instead
+    pub(crate) fn nodes_update(&self, root: H::Fr, nodes: Nodes<H>) -> Update<H> {
+        let mut update = Update::default();
+        for &(key, value) in &nodes {
+            let db_key = key.to_db_key(self.tree_id);
+            if value == self.cache[key.0] {
+                update.writes.delete(db_key);
+            } else {
+                update.writes.put(db_key, H::serialize(value));
+            }
+        }
+        update.root = Some(root);
+        update.nodes = nodes;
+
+        update
+    }
+
+    // Applies the in-memory changes of the update whose writes are stored
+    pub(crate) fn apply(&mut self, update: Update<H>) {
+        if let Some(root) = update.root {
+            self.root = root;
+        }
+        if let Some(next_index) = update.next_index {
+            self.next_index = next_index;
+        }
+        if let Some(occupied) = update.occupied {
+            self.occupied = occupied;
+        }
+        if let Some(free_list) = update.free_list {
+            self.free_list = Some(free_list);
+        }
+        self.leaf_index |= update.leaf_index;
+
+        if let Some(mut node_cache) = self.lock_node_cache() {
+            for (key, value) in update.nodes {
+                node_cache.insert(key, value);
+            }
+        }
+    }
+
+    // Keys read by `set`: the path siblings of the leaf followed by the replaced leaf
+    pub(crate) fn set_keys(&self, index: usize) -> PmtreeResult<Vec<Key>> {
+        if index >= self.capacity() {
+            return Err(PmtreeErrorKind::TreeError(TreeErrorKind::IndexOutOfBounds));
+        }
+
+        let mut keys = path_siblings(self.depth, index);
+        keys.push(Key(self.depth, index));
+
+        Ok(keys)
+    }
+
+    // Sets the leaf given the nodes read by `set_keys`.
+    // Returns the new root, the recalculated nodes (including the leaf) and the replaced leaf
+    pub(crate) fn set(
+        &self,
+        index: usize,
+        leaf: H::Fr,
+        mut siblings: Vec<H::Fr>,
+    ) -> (H::Fr, Nodes<H>, H::Fr) {
+        let old_leaf = siblings.pop().unwrap();
+
+        let mut nodes = recalculate_path(&*self.hasher, self.depth, index, leaf, siblings);
+        let root = nodes.last().map_or(leaf, |&(_, root)| root);
+        nodes.push((Key(self.depth, index), leaf));
+
+        (root, nodes, old_leaf)
+    }
+
+    // Keys read by batch insertion of `len` leaves from `start`:
+    // the siblings on the edges of the run followed by the replaced leaves
+    pub(crate) fn batch_keys(&self, start: usize, len: usize) -> PmtreeResult<Vec<Key>> {
+        let end = start + len;
+        if end > self.capacity() {
+            return Err(PmtreeErrorKind::TreeError(TreeErrorKind::MerkleTreeIsFull));
+        }
+
+        let mut keys = edge_keys(self.depth, start, len);
+        keys.extend(self.leaf_keys(start..end));
+
+        Ok(keys)
+    }
+
+    // Prepares the recalculation of the tree above the run of `leaves` starting at `start`,
+    // given the nodes read by `batch_keys`. Returns it with the replaced leaves
+    pub(crate) fn recalculation(
+        &self,
+        start: usize,
+        leaves: Vec<H::Fr>,
+        keys: Vec<Key>,
+        mut values: Vec<H::Fr>,
+    ) -> (Recalculation<H>, Vec<H::Fr>) {
+        let old_leaves = values.split_off(values.len() - leaves.len());
+        let edge = keys.into_iter().zip(values).collect();
+
+        let recalculation = Recalculation {
+            hasher: Arc::clone(&self.hasher),
+            depth: self.depth,
+            start,
+            parallel: leaves.len() >= self.parallel_threshold,
+            leaves,
+            edge,
+            pool: self.thread_pool.clone(),
+        };
+
+        (recalculation, old_leaves)
+    }
+
+    // Returns the leaf index changes for the run of `old_leaves` starting at `start`
+    // replaced by `leaves`, `None` if the leaf index is disabled
+    pub(crate) fn leaf_index_update(
+        &self,
+        start: usize,
+        old_leaves: &[H::Fr],
+        leaves: &[H::Fr],
+    ) -> Option<LeafIndexUpdate> {
+        if !self.leaf_index {
+            return None;
+        }
+
+        let default_leaf = self.default_leaf();
+        let mut update = LeafIndexUpdate::new(self.tree_id);
+        for (index, (old, new)) in (start..).zip(old_leaves.iter().zip(leaves)) {
+            if old == new {
+                continue;
+            }
+            if old != default_leaf {
+                update.remove(H::serialize(*old), index);
+            }
+            if new != default_leaf {
+                update.add(H::serialize(*new), index);
+            }
+        }
+
+        Some(update)
+    }
+
+    // Adds the changes of the free list, the number of non-default leaves and next_index
+    // to the update replacing the run of `old_leaves` starting at `start` by `leaves`
+    pub(crate) fn commit_leaves(
+        &self,
+        update: &mut Update<H>,
+        start: usize,
+        old_leaves: &[H::Fr],
+        leaves: &[H::Fr],
+    ) {
+        let default_leaf = self.default_leaf();
+        if let Some(free_list) = &self.free_list {
+            let mut free_list = free_list.clone();
+            if free_list.update(self.next_index, start, leaves, default_leaf) {
+                update
+                    .writes
+                    .put(metadata_key(self.tree_id, FREE_LIST), free_list.encode());
+                update.free_list = Some(free_list);
+            }
+        }
+
+        let occupied = count_occupied(self.occupied, default_leaf, old_leaves, leaves);
+        if occupied != self.occupied {
+            update.writes.put(
+                metadata_key(self.tree_id, OCCUPIED),
+                occupied.to_be_bytes().to_vec(),
+            );
+            update.occupied = Some(occupied);
+        }
+
+        let end = start + leaves.len();
+        if end > self.next_index {
+            update.writes.put(
+                metadata_key(self.tree_id, NEXT_INDEX),
+                end.to_be_bytes().to_vec(),
+            );
+            update.next_index = Some(end);
+        }
+    }
+
+    // Adds the recount of the non-default leaves and the rebuilt free list (if enabled)
+    // from the leaves below next_index to the update, writing all the leaf counters
+    pub(crate) fn recount(&self, update: &mut Update<H>, leaves: &[H::Fr]) {
+        let occupied = leaves
+            .iter()
+            .filter(|&leaf| leaf != self.default_leaf())
+            .count();
+        update
+            .writes
+            .extend(self.counters(self.next_index, occupied));
+        update.occupied = Some(occupied);
+
+        if self.free_list.is_some() {
+            let free_list = self.enable_free_list(leaves);
+            update.writes.extend(free_list.writes);
+            update.free_list = free_list.free_list;
+        }
+    }
+
+    // Returns the leaf index entries of the leaves below next_index
+    pub(crate) fn index_leaves(&self, leaves: &[H::Fr]) -> LeafIndexUpdate {
+        let mut update = LeafIndexUpdate::new(self.tree_id);
+        for (index, leaf) in leaves.iter().enumerate() {
+            if leaf != self.default_leaf() {
+                update.add(H::serialize(*leaf), index);
+            }
+        }
+
+        update
+    }
+
+    // Returns the update enabling the leaf index, writing its marker
+    pub(crate) fn enable_leaf_index(&self) -> Update<H> {
+        let mut update = Update::default();
+        update
+            .writes
+            .put(metadata_key(self.tree_id, LEAF_INDEX), Vec::new());
+        update.leaf_index = true;
+
+        update
+    }
+
+    // Returns the key of the leaf index bucket holding the leaf
+    pub(crate) fn bucket_key(&self, leaf: &H::Fr) -> PmtreeResult<DBKey> {
+        if !self.leaf_index {
+            return Err(PmtreeErrorKind::TreeError(TreeErrorKind::LeafIndexDisabled));
+        }
+
+        Ok(crate::leaf_index::bucket_key(
+            self.tree_id,
+            &H::serialize(*leaf),
+        ))
+    }
+
+    // Finds the lowest index of the leaf in the bucket read by `bucket_key`
+    pub(crate) fn find_leaf(
+        &self,
+        leaf: &H::Fr,
+        bucket: Option<Value>,
+    ) -> PmtreeResult<Option<usize>> {
+        let leaf = H::serialize(*leaf);
+
+        Ok(decode_bucket(bucket)?
+            .into_iter()
+            .find(|entry| entry.0 == leaf)
+            .map(|entry| entry.1))
+    }
+
+    // Returns the update enabling the free list collected from the leaves below next_index
+    pub(crate) fn enable_free_list(&self, leaves: &[H::Fr]) -> Update<H> {
+        let mut free_list = FreeList::default();
+        free_list.update(0, 0, leaves, self.default_leaf());
+
+        let mut update = Update::default();
+        update
+            .writes
+            .put(metadata_key(self.tree_id, FREE_LIST), free_list.encode());
+        update.free_list = Some(free_list);
+
+        update
+    }
+
+    // Keys read by `proof`: the path siblings of the leaf
+    pub(crate) fn proof_keys(&self, index: usize) -> PmtreeResult<Vec<Key>> {
+        if index >= self.capacity() {
+            return Err(PmtreeErrorKind::TreeError(TreeErrorKind::IndexOutOfBounds));
+        }
+
+        Ok(path_siblings(self.depth, index))
+    }
+
+    // Builds the proof from the siblings read by the keys
+    pub(crate) fn proof(&self, keys: &[Key], siblings: Vec<H::Fr>) -> MerkleProof<H> {
+        build_proof(&*self.hasher, self.depth, keys, siblings)
+    }
+
+    pub(crate) fn verify(&self, leaf: &H::Fr, witness: &MerkleProof<H>) -> bool {
+        witness.length() == self.depth && witness.verify_with_hasher(&self.hasher, leaf, &self.root)
+    }
+
+    pub(crate) fn set_num_threads(&mut self, num_threads: usize) -> PmtreeResult<()> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .map_err(|e| {
+                PmtreeErrorKind::TreeError(TreeErrorKind::ThreadPoolError(e.to_string()))
+            })?;
+        self.thread_pool = Some(Arc::new(pool));
+
+        Ok(())
+    }
+
+    pub(crate) fn set_node_cache(&mut self, pinned_levels: usize, capacity: usize) {
+        self.node_cache = Some(Mutex::new(NodeCache::new(pinned_levels, capacity)));
+    }
+
+    pub(crate) fn cache_stats(&self) -> Option<CacheStats> {
+        self.lock_node_cache().map(|node_cache| node_cache.stats())
+    }
+}
+
+// Batch recalculation detached from the tree state, so it can run on another thread
+pub(crate) struct Recalculation<H: Hasher> {
+    hasher: Arc<H>,
+    depth: usize,
+    start: usize,
+    leaves: Vec<H::Fr>,
+    edge: HashMap<Key, H::Fr>,
+    parallel: bool,
+    pool: Option<Arc<rayon::ThreadPool>>,
+}
+
+impl<H: Hasher> Recalculation<H> {
+    // Returns the root and all the visited nodes (including the leaves and the edges)
+    pub(crate) fn run(self) -> (H::Fr, Nodes<H>) {
+        recalculate_levels(
+            &*self.hasher,
+            self.depth,
+            self.start,
+            self.leaves,
+            self.edge,
+            self.parallel,
+            self.pool.as_deref(),
+        )
+    }
+}
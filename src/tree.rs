@@ -1,27 +1,419 @@
+use crate::free_list::FreeList;
+use crate::leaf_index::LeafIndexUpdate;
+use crate::snapshot::{read_leaf, snapshot_error, write_leaf, ImportDb, SNAPSHOT_VERSION};
+use crate::state::{check_batch, TreeState, Update, Writes};
 use crate::*;
 
+use rayon::prelude::*;
 use std::cmp::{max, min};
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{Read, Write};
+use std::mem;
+use std::ops::Range;
+use std::sync::Arc;
 
-// db[DEPTH_KEY] = depth
-const DEPTH_KEY: DBKey = (u64::MAX - 1).to_be_bytes();
+// Default minimal number of leaves for which batch operations run in parallel
+pub(crate) const DEFAULT_PARALLEL_THRESHOLD: usize = 256;
 
-// db[NEXT_INDEX_KEY] = next_index;
-const NEXT_INDEX_KEY: DBKey = u64::MAX.to_be_bytes();
+// Number of leaves read in one batch by export from dbs without range scans
+const EXPORT_BATCH: usize = 4096;
 
-// Default tree depth
-const DEFAULT_TREE_DEPTH: usize = 20;
+/// Identifies a tree inside a shared database
+pub type TreeId = u64;
 
-// Denotes keys (depth, index) in Merkle Tree. Can be converted to DBKey
-// TODO! Think about using hashing for that
+/// Id of the tree created by `new` and opened by `load`
+pub const DEFAULT_TREE_ID: TreeId = 0;
+
+// Namespace holding the registry of the trees in a database
+pub(crate) const REGISTRY_TREE_ID: TreeId = TreeId::MAX;
+
+// Tag of metadata keys, node keys are tagged by their depth
+const METADATA_TAG: u8 = u8::MAX;
+
+// db[metadata_key(tree_id, HEADER)] = header
+pub(crate) const HEADER: u64 = 0;
+
+// db[metadata_key(tree_id, DEPTH)] = depth
+pub(crate) const DEPTH: u64 = 1;
+
+// db[metadata_key(tree_id, NEXT_INDEX)] = next_index
+pub(crate) const NEXT_INDEX: u64 = 2;
+
+// db[metadata_key(tree_id, LEAF_INDEX)] is present if the leaf index is enabled
+pub(crate) const LEAF_INDEX: u64 = 3;
+
+// db[metadata_key(tree_id, FREE_LIST)] = freed index ranges, present if the free list is enabled
+pub(crate) const FREE_LIST: u64 = 4;
+
+// db[metadata_key(tree_id, OCCUPIED)] = number of non-default leaves
+pub(crate) const OCCUPIED: u64 = 5;
+
+/// Maximal depth of the tree, indexes are encoded with 7 bytes
+pub const MAX_DEPTH: usize = 56;
+
+// Builds db key: tree id (8 bytes) | tag (1 byte) | payload (7 bytes, big-endian)
+pub(crate) fn db_key(tree_id: TreeId, tag: u8, payload: u64) -> DBKey {
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&tree_id.to_be_bytes());
+    bytes[8..].copy_from_slice(&payload.to_be_bytes());
+    bytes[8] = tag;
+    bytes
+}
+
+// Builds db key of the tree metadata record
+pub(crate) fn metadata_key(tree_id: TreeId, id: u64) -> DBKey {
+    db_key(tree_id, METADATA_TAG, id)
+}
+
+// Builds db key of the registry record of the tree: registry id (8 bytes) | tree id (8 bytes).
+// Every tree has its own record, so trees created and dropped concurrently
+// through clones of one db handle are never lost from the registry
+pub(crate) fn registry_key(tree_id: TreeId) -> DBKey {
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&REGISTRY_TREE_ID.to_be_bytes());
+    bytes[8..].copy_from_slice(&tree_id.to_be_bytes());
+    bytes
+}
+
+// Denotes keys (depth, index) in Merkle Tree. Converted to DBKey inside the tree namespace,
+// with depth as the tag and index as the payload, so keys of a level are ordered by index
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct Key(usize, usize);
-impl From<Key> for DBKey {
-    fn from(key: Key) -> Self {
-        let cantor_pairing = ((key.0 + key.1) * (key.0 + key.1 + 1) / 2 + key.1) as u64;
-        cantor_pairing.to_be_bytes()
+pub struct Key(pub(crate) usize, pub(crate) usize);
+
+impl Key {
+    /// Creates the key of the node at the level (root is level 0) and the index within the level
+    pub fn new(level: usize, index: usize) -> Self {
+        Key(level, index)
+    }
+
+    /// Returns the level of the node, root is level 0
+    pub fn level(&self) -> usize {
+        self.0
+    }
+
+    /// Returns the index of the node within its level
+    pub fn index(&self) -> usize {
+        self.1
     }
+
+    // Callers keep the level within `MAX_DEPTH + 1` and the index below `1 << MAX_DEPTH`,
+    // larger ones don't fit the key and would alias other keys
+    pub(crate) fn to_db_key(self, tree_id: TreeId) -> DBKey {
+        debug_assert!(self.0 <= MAX_DEPTH + 1 && self.1 < 1 << MAX_DEPTH);
+        db_key(tree_id, self.0 as u8, self.1 as u64)
+    }
+
+    pub(crate) fn from_db_key(bytes: &DBKey) -> Self {
+        let mut index = [0; 8];
+        index[1..].copy_from_slice(&bytes[9..]);
+        Key(bytes[8] as usize, u64::from_be_bytes(index) as usize)
+    }
+}
+
+/// Returns ids of the trees stored in the database, in ascending order.
+/// Requires `Database::iter_range` support
+pub fn list_trees<D: Database>(db: &D) -> PmtreeResult<Vec<TreeId>> {
+    db.iter_range(registry_key(0), registry_key(REGISTRY_TREE_ID))?
+        .map(|entry| entry.map(|(key, _)| TreeId::from_be_bytes(key[8..].try_into().unwrap())))
+        .collect()
+}
+
+/// Removes the tree with all its nodes and metadata from the database.
+/// Requires `Database::iter_range` support
+pub fn drop_tree<D: Database>(db: &mut D, tree_id: TreeId) -> PmtreeResult<()> {
+    if tree_id == REGISTRY_TREE_ID {
+        return Err(PmtreeErrorKind::TreeError(TreeErrorKind::ReservedTreeId));
+    }
+
+    let from = db_key(tree_id, 0, 0);
+    let to = db_key(tree_id + 1, 0, 0);
+
+    let mut keys = db
+        .iter_range(from, to)?
+        .map(|entry| entry.map(|(key, _)| key))
+        .collect::<PmtreeResult<Vec<_>>>()?;
+    keys.push(registry_key(tree_id));
+
+    db.delete_batch(keys)
+}
+
+// Nodes with their keys, as collected by batch recalculation
+pub(crate) type Nodes<H> = Vec<(Key, <H as Hasher>::Fr)>;
+
+// Hashes two children into their parent node at the specified level,
+// applying leaf hashing when the children are leaves
+pub(crate) fn hash_children<H: Hasher>(
+    hasher: &H,
+    depth: usize,
+    level: usize,
+    left: H::Fr,
+    right: H::Fr,
+) -> H::Fr {
+    if level + 1 == depth {
+        hasher.hash_node(level, &[hasher.hash_leaf(left), hasher.hash_leaf(right)])
+    } else {
+        hasher.hash_node(level, &[left, right])
+    }
+}
+
+// Reads a usize metadata value stored by the key
+fn read_metadata<D: Database>(db: &D, key: DBKey, name: &'static str) -> PmtreeResult<usize> {
+    decode_metadata(db.get(key)?, name)
+}
+
+// Decodes a usize metadata value
+pub(crate) fn decode_metadata(value: Option<Value>, name: &'static str) -> PmtreeResult<usize> {
+    let value = value.ok_or(PmtreeErrorKind::DatabaseError(
+        DatabaseErrorKind::MissingMetadata(name),
+    ))?;
+
+    let bytes = value
+        .try_into()
+        .map_err(|_| PmtreeErrorKind::DatabaseError(DatabaseErrorKind::MalformedMetadata(name)))?;
+
+    Ok(usize::from_be_bytes(bytes))
+}
+
+// Deserializes the node stored by the key, reporting the key if the value is corrupted
+pub(crate) fn deserialize_node<H: Hasher>(key: Key, value: Value) -> PmtreeResult<H::Fr> {
+    H::deserialize(value).map_err(|_| PmtreeErrorKind::TreeError(TreeErrorKind::CorruptedNode(key)))
+}
+
+// Returns default (empty) nodes for every level, from root (0) to leaves (depth)
+pub(crate) fn default_nodes<H: Hasher>(hasher: &H, depth: usize) -> PmtreeResult<Vec<H::Fr>> {
+    let cache = hasher.zero_values(depth);
+    if cache.len() != depth + 1 {
+        return Err(PmtreeErrorKind::TreeError(TreeErrorKind::InvalidZeroValues));
+    }
+
+    Ok(cache)
+}
+
+// Checks that a new tree with the id and depth can be created,
+// `stored_depth` is the depth record already stored under the id
+pub(crate) fn check_new_tree(
+    tree_id: TreeId,
+    depth: usize,
+    stored_depth: Option<Value>,
+) -> PmtreeResult<()> {
+    if depth > MAX_DEPTH {
+        return Err(PmtreeErrorKind::TreeError(TreeErrorKind::DepthTooLarge));
+    }
+
+    if tree_id == REGISTRY_TREE_ID {
+        return Err(PmtreeErrorKind::TreeError(TreeErrorKind::ReservedTreeId));
+    }
+
+    if stored_depth.is_some() {
+        return Err(PmtreeErrorKind::DatabaseError(
+            DatabaseErrorKind::TreeExists(tree_id),
+        ));
+    }
+
+    Ok(())
+}
+
+// Validates the stored metadata records of the tree opened with the hasher `H`.
+// Returns depth and next_index
+pub(crate) fn check_metadata<H: Hasher>(
+    depth: Option<Value>,
+    header: Option<Value>,
+    next_index: Option<Value>,
+    expected_depth: Option<usize>,
+) -> PmtreeResult<(usize, usize)> {
+    let depth = decode_metadata(depth, "depth")?;
+    if depth > MAX_DEPTH {
+        return Err(PmtreeErrorKind::DatabaseError(
+            DatabaseErrorKind::MalformedMetadata("depth"),
+        ));
+    }
+
+    // Validate header against the opening tree
+    let header = header.ok_or(PmtreeErrorKind::DatabaseError(
+        DatabaseErrorKind::MissingMetadata("header"),
+    ))?;
+    TreeHeader::from_bytes(&header)?.check_compatible(&TreeHeader::new::<H>(depth))?;
+
+    if let Some(expected) = expected_depth {
+        if depth != expected {
+            return Err(PmtreeErrorKind::TreeError(TreeErrorKind::DepthMismatch {
+                expected,
+                actual: depth,
+            }));
+        }
+    }
+
+    let next_index = decode_metadata(next_index, "next_index")?;
+    if next_index > 1 << depth {
+        return Err(PmtreeErrorKind::DatabaseError(
+            DatabaseErrorKind::MalformedMetadata("next_index"),
+        ));
+    }
+
+    Ok((depth, next_index))
+}
+
+// Returns the number of non-default leaves after the run of `old` leaves is replaced by `new`.
+// An inconsistent stored count doesn't go below zero
+pub(crate) fn count_occupied<F: PartialEq>(
+    occupied: usize,
+    default: &F,
+    old: &[F],
+    new: &[F],
+) -> usize {
+    old.iter().zip(new).fold(occupied, |count, (old, new)| {
+        match (old != default, new != default) {
+            (false, true) => count + 1,
+            (true, false) => count.saturating_sub(1),
+            _ => count,
+        }
+    })
+}
+
+// Returns keys of the siblings on the path from the leaf to the root
+pub(crate) fn path_siblings(depth: usize, index: usize) -> Vec<Key> {
+    (1..=depth)
+        .rev()
+        .map(|level| Key(level, (index >> (depth - level)) ^ 1))
+        .collect()
+}
+
+// Recalculates the nodes on the path from the leaf to the root, given the path siblings.
+// Returns the nodes from the parent of the leaf to the root
+pub(crate) fn recalculate_path<H: Hasher>(
+    hasher: &H,
+    depth: usize,
+    index: usize,
+    leaf: H::Fr,
+    siblings: Vec<H::Fr>,
+) -> Nodes<H> {
+    let mut nodes = Vec::with_capacity(depth);
+
+    let mut value = leaf;
+    let mut i = index;
+    for (level, sibling) in (1..=depth).rev().zip(siblings) {
+        value = if i & 1 == 0 {
+            hash_children(hasher, depth, level - 1, value, sibling)
+        } else {
+            hash_children(hasher, depth, level - 1, sibling, value)
+        };
+        i >>= 1;
+        nodes.push((Key(level - 1, i), value));
+    }
+
+    nodes
+}
+
+// Returns keys of the siblings completing the pairs on the edges of the run on every level
+pub(crate) fn edge_keys(depth: usize, start: usize, len: usize) -> Vec<Key> {
+    let mut keys = Vec::with_capacity(2 * depth);
+
+    let (mut start, mut end) = (start, start + len);
+    for level in (1..=depth).rev() {
+        if start % 2 == 1 {
+            start -= 1;
+            keys.push(Key(level, start));
+        }
+        if (end - start) % 2 == 1 {
+            keys.push(Key(level, end));
+            end += 1;
+        }
+        start >>= 1;
+        end >>= 1;
+    }
+
+    keys
+}
+
+// Recalculates the tree above the contiguous run of `leaves` starting at `start`, level by level.
+// `edge` holds the siblings on the edges of the run, as listed by `edge_keys`.
+// Returns the root and all the visited nodes (including the leaves and the edges)
+pub(crate) fn recalculate_levels<H: Hasher>(
+    hasher: &H,
+    depth: usize,
+    start: usize,
+    leaves: Vec<H::Fr>,
+    edge: HashMap<Key, H::Fr>,
+    parallel: bool,
+    pool: Option<&rayon::ThreadPool>,
+) -> (H::Fr, Nodes<H>) {
+    let mut updated = Vec::with_capacity(2 * leaves.len() + depth);
+
+    let mut start = start;
+    let mut nodes = leaves;
+    for level in (0..depth).rev() {
+        let child_level = level + 1;
+
+        // Complete the pairs on the edges
+        if start % 2 == 1 {
+            start -= 1;
+            nodes.insert(0, edge[&Key(child_level, start)]);
+        }
+        if nodes.len() % 2 == 1 {
+            nodes.push(edge[&Key(child_level, start + nodes.len())]);
+        }
+
+        updated.extend(
+            nodes
+                .iter()
+                .enumerate()
+                .map(|(i, &value)| (Key(child_level, start + i), value)),
+        );
+
+        nodes = hash_level(hasher, depth, level, &nodes, parallel, pool);
+        start >>= 1;
+    }
+
+    let root = nodes[0];
+    updated.push((Key(0, 0), root));
+
+    (root, updated)
+}
+
+// Hashes pairs of children into the nodes of the specified level.
+// In parallel mode every rayon task owns its chunk, so no locking is involved
+fn hash_level<H: Hasher>(
+    hasher: &H,
+    depth: usize,
+    level: usize,
+    children: &[H::Fr],
+    parallel: bool,
+    pool: Option<&rayon::ThreadPool>,
+) -> Vec<H::Fr> {
+    let hash = |pair: &[H::Fr]| hash_children(hasher, depth, level, pair[0], pair[1]);
+
+    if !parallel {
+        return children.chunks(2).map(hash).collect();
+    }
+
+    let hash_parallel = || children.par_chunks(2).map(hash).collect();
+    match pool {
+        Some(pool) => pool.install(hash_parallel),
+        None => hash_parallel(),
+    }
+}
+
+// Builds the Merkle proof from the path siblings of the leaf
+pub(crate) fn build_proof<H: Hasher>(
+    hasher: &H,
+    depth: usize,
+    keys: &[Key],
+    siblings: Vec<H::Fr>,
+) -> MerkleProof<H> {
+    let witness = keys
+        .iter()
+        .zip(siblings)
+        .map(|(key, sibling)| {
+            let sibling = if key.0 == depth {
+                hasher.hash_leaf(sibling)
+            } else {
+                sibling
+            };
+            (sibling, (1 - (key.1 & 1)).try_into().unwrap())
+        })
+        .collect();
+
+    MerkleProof(witness)
 }
 
 /// The Merkle Tree structure
@@ -30,15 +422,39 @@ where
     D: Database,
     H: Hasher,
 {
-    pub db: D,
-    depth: usize,
-    next_index: usize,
-    cache: Vec<H::Fr>,
-    root: H::Fr,
+    db: D,
+    state: TreeState<H>,
+}
+
+/// The integrity check report
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// Internal nodes that don't match the hash of their children
+    pub mismatched_nodes: Vec<Key>,
+    /// Nodes that can't be deserialized
+    pub corrupted_nodes: Vec<Key>,
+    /// Whether the stored root differs from the tree root
+    pub root_mismatch: bool,
+    /// Whether the stored next_index can't be read, differs from the tree one
+    /// or is below some stored leaves
+    pub next_index_mismatch: bool,
+    /// Stored leaves beyond next_index, left by an interrupted update.
+    /// Found only if the db supports range scans
+    pub stray_leaves: Vec<usize>,
+}
+
+impl IntegrityReport {
+    /// Returns true if no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.mismatched_nodes.is_empty()
+            && self.corrupted_nodes.is_empty()
+            && !self.root_mismatch
+            && !self.next_index_mismatch
+            && self.stray_leaves.is_empty()
+    }
 }
 
 /// The Merkle proof structure
-#[derive(Clone, PartialEq, Eq)]
 pub struct MerkleProof<H: Hasher>(pub Vec<(H::Fr, u8)>);
 
 impl<D, H> MerkleTree<D, H>
@@ -47,82 +463,280 @@ where
     H: Hasher,
 {
     /// Creates tree with specified depth and default "pmtree_db" dbpath.
-    pub fn default(depth: usize) -> PmtreeResult<Self> {
+    pub fn default(depth: usize) -> PmtreeResult<Self>
+    where
+        H: Default,
+    {
         Self::new(depth, D::Config::default())
     }
 
     /// Creates new `MerkleTree` and store it to the specified path/db
-    pub fn new(depth: usize, db_config: D::Config) -> PmtreeResult<Self> {
-        // Create new db instance
-        let mut db = D::new(db_config)?;
-
-        // Insert depth val into db
-        let depth_val = depth.to_be_bytes().to_vec();
-        db.put(DEPTH_KEY, depth_val)?;
-
-        // Insert next_index val into db
-        let next_index = 0usize;
-        let next_index_val = next_index.to_be_bytes().to_vec();
-        db.put(NEXT_INDEX_KEY, next_index_val)?;
-
-        // Cache nodes
-        let mut cache = vec![H::default_leaf(); depth + 1];
-
-        // Initialize one branch of the `Merkle Tree` from bottom to top
-        cache[depth] = H::default_leaf();
-        db.put(Key(depth, 0).into(), H::serialize(cache[depth]))?;
-        for i in (0..depth).rev() {
-            cache[i] = H::hash(&[cache[i + 1], cache[i + 1]]);
-            db.put(Key(i, 0).into(), H::serialize(cache[i]))?;
-        }
-
-        let root = cache[0];
-
-        Ok(Self {
-            db,
-            depth,
-            next_index,
-            cache,
-            root,
-        })
+    pub fn new(depth: usize, db_config: D::Config) -> PmtreeResult<Self>
+    where
+        H: Default,
+    {
+        Self::with_hasher(depth, db_config, H::default())
+    }
+
+    /// Creates new `MerkleTree` that hashes through the specified hasher instance
+    pub fn with_hasher(depth: usize, db_config: D::Config, hasher: H) -> PmtreeResult<Self> {
+        Self::create(D::new(db_config)?, DEFAULT_TREE_ID, depth, hasher)
+    }
+
+    /// Creates new `MerkleTree` in the already opened db
+    pub fn new_with_db(db: D, depth: usize) -> PmtreeResult<Self>
+    where
+        H: Default,
+    {
+        Self::create(db, DEFAULT_TREE_ID, depth, H::default())
+    }
+
+    /// Creates new `MerkleTree` with the specified id in the (possibly shared) db,
+    /// all its keys are prefixed with the id
+    pub fn new_in(db: D, tree_id: TreeId, depth: usize) -> PmtreeResult<Self>
+    where
+        H: Default,
+    {
+        Self::new_in_with_hasher(db, tree_id, depth, H::default())
+    }
+
+    /// Creates new `MerkleTree` with the specified id in the (possibly shared) db
+    /// that hashes through the specified hasher instance
+    pub fn new_in_with_hasher(
+        db: D,
+        tree_id: TreeId,
+        depth: usize,
+        hasher: H,
+    ) -> PmtreeResult<Self> {
+        Self::create(db, tree_id, depth, hasher)
+    }
+
+    // Creates the tree in the db namespace
+    fn create(db: D, tree_id: TreeId, depth: usize, hasher: H) -> PmtreeResult<Self> {
+        let values = db.get_batch(&TreeState::<H>::create_keys(tree_id))?;
+        let (state, writes) = TreeState::create(tree_id, depth, hasher, values)?;
+
+        let mut tree = Self { db, state };
+        tree.write(writes)?;
+
+        Ok(tree)
     }
 
     /// Loads existing Merkle Tree from the specified path/db
-    pub fn load(db_config: D::Config) -> PmtreeResult<Self> {
-        // Load existing db instance
-        let db = D::load(db_config)?;
-
-        // Load root
-        let root = match db.get(Key(0, 0).into())? {
-            Some(root) => H::deserialize(root),
-            None => H::default_leaf(),
-        };
+    pub fn load(db_config: D::Config) -> PmtreeResult<Self>
+    where
+        H: Default,
+    {
+        Self::load_with_hasher(db_config, H::default())
+    }
 
-        // Load depth & next_index values from db
-        let depth = match db.get(DEPTH_KEY)? {
-            Some(depth) => usize::from_be_bytes(depth.try_into().unwrap()),
-            None => DEFAULT_TREE_DEPTH,
-        };
+    /// Loads existing Merkle Tree, failing if its depth differs from the expected one
+    pub fn load_with_expected_depth(db_config: D::Config, depth: usize) -> PmtreeResult<Self>
+    where
+        H: Default,
+    {
+        Self::open(
+            D::load(db_config)?,
+            DEFAULT_TREE_ID,
+            H::default(),
+            Some(depth),
+        )
+    }
+
+    /// Loads existing Merkle Tree that hashes through the specified hasher instance
+    pub fn load_with_hasher(db_config: D::Config, hasher: H) -> PmtreeResult<Self> {
+        Self::open(D::load(db_config)?, DEFAULT_TREE_ID, hasher, None)
+    }
+
+    /// Loads existing Merkle Tree from the already opened db
+    pub fn load_with_db(db: D) -> PmtreeResult<Self>
+    where
+        H: Default,
+    {
+        Self::open(db, DEFAULT_TREE_ID, H::default(), None)
+    }
+
+    /// Loads existing Merkle Tree with the specified id from the (possibly shared) db
+    pub fn load_in(db: D, tree_id: TreeId) -> PmtreeResult<Self>
+    where
+        H: Default,
+    {
+        Self::load_in_with_hasher(db, tree_id, H::default())
+    }
+
+    /// Loads existing Merkle Tree with the specified id from the (possibly shared) db
+    /// that hashes through the specified hasher instance
+    pub fn load_in_with_hasher(db: D, tree_id: TreeId, hasher: H) -> PmtreeResult<Self> {
+        Self::open(db, tree_id, hasher, None)
+    }
+
+    // Opens the tree stored in the db namespace, validating its metadata
+    fn open(
+        db: D,
+        tree_id: TreeId,
+        hasher: H,
+        expected_depth: Option<usize>,
+    ) -> PmtreeResult<Self> {
+        let values = db.get_batch(&TreeState::<H>::open_keys(tree_id))?;
+        let state = TreeState::open(tree_id, hasher, expected_depth, values)?;
+
+        Ok(Self { db, state })
+    }
+
+    /// Rebuilds the tree from a snapshot written by `export` in a new db.
+    /// The nodes are recomputed by batch insertion and the root is verified
+    pub fn import<R: Read>(reader: R, db_config: D::Config) -> PmtreeResult<Self>
+    where
+        H: Default,
+    {
+        Self::import_with_db(reader, D::new(db_config)?)
+    }
+
+    /// Rebuilds the tree from a snapshot written by `export` in the already opened db
+    pub fn import_with_db<R: Read>(reader: R, db: D) -> PmtreeResult<Self>
+    where
+        H: Default,
+    {
+        Self::import_with(reader, db, DEFAULT_TREE_ID, H::default())
+    }
+
+    /// Rebuilds the tree from a snapshot written by `export` under the specified id
+    /// of the (possibly shared) db, hashing through the specified hasher instance.
+    /// The snapshot header is validated before anything is written to the db,
+    /// and a failed import leaves nothing in the db
+    pub fn import_with<R: Read>(
+        mut reader: R,
+        db: D,
+        tree_id: TreeId,
+        hasher: H,
+    ) -> PmtreeResult<Self> {
+        let header = SnapshotHeader::read(&mut reader)?;
+        if header.hasher_id != H::ID {
+            return Err(snapshot_error(SnapshotErrorKind::HasherMismatch {
+                expected: H::ID.to_string(),
+                actual: header.hasher_id,
+            }));
+        }
+
+        if header.depth > MAX_DEPTH {
+            return Err(snapshot_error(SnapshotErrorKind::Malformed("depth")));
+        }
+
+        if header.next_index > 1 << header.depth {
+            return Err(snapshot_error(SnapshotErrorKind::Malformed("next_index")));
+        }
+
+        if header.leaves > header.next_index {
+            return Err(snapshot_error(SnapshotErrorKind::Malformed("leaves")));
+        }
+
+        let root = H::deserialize(header.root.clone())
+            .map_err(|_| snapshot_error(SnapshotErrorKind::Malformed("root")))?;
+
+        // Everything stored by a failed import is removed, retrying it starts from scratch
+        let mut tree = MerkleTree::create(ImportDb::from_db(db), tree_id, header.depth, hasher)?;
+        match tree.import_leaves(reader, &header, root) {
+            Ok(()) => Ok(tree.map_db(|db| db.db)),
+            Err(e) => {
+                // The import error is reported even if the cleanup fails
+                let _ = tree.db_mut().discard();
+                Err(e)
+            }
+        }
+    }
+
+    // Inserts the leaves read from the snapshot, checking the resulting root
+    fn import_leaves<R: Read>(
+        &mut self,
+        mut reader: R,
+        header: &SnapshotHeader,
+        root: H::Fr,
+    ) -> PmtreeResult<()> {
+        // Consecutive leaves are inserted in one batch
+        let mut run_start = 0;
+        let mut run = Vec::new();
+        for _ in 0..header.leaves {
+            let (index, leaf) = read_leaf(&mut reader)?;
+            if index >= header.next_index || index < run_start + run.len() {
+                return Err(snapshot_error(SnapshotErrorKind::Malformed("leaf index")));
+            }
+
+            let leaf = H::deserialize(leaf)
+                .map_err(|_| snapshot_error(SnapshotErrorKind::Malformed("leaf")))?;
 
-        let next_index = match db.get(NEXT_INDEX_KEY)? {
-            Some(next_index) => usize::from_be_bytes(next_index.try_into().unwrap()),
-            None => 0,
+            if index != run_start + run.len() {
+                self.batch_insert(Some(run_start), &run)?;
+                run.clear();
+                run_start = index;
+            }
+            run.push(leaf);
+        }
+        self.batch_insert(Some(run_start), &run)?;
+
+        // Restore next_index past the trailing default leaves
+        if self.state.next_index < header.next_index {
+            let default_leaf = *self.state.default_leaf();
+            self.batch_insert(Some(header.next_index - 1), &[default_leaf])?;
+        }
+
+        if self.state.root != root {
+            return Err(snapshot_error(SnapshotErrorKind::RootMismatch));
+        }
+
+        Ok(())
+    }
+
+    /// Writes a portable snapshot of the tree: the header (depth, hasher id, next_index, root)
+    /// followed by the non-default leaves, streamed in index order
+    pub fn export<W: Write>(&self, mut writer: W) -> PmtreeResult<()> {
+        SnapshotHeader {
+            version: SNAPSHOT_VERSION,
+            hasher_id: H::ID.to_string(),
+            depth: self.state.depth,
+            next_index: self.state.next_index,
+            root: H::serialize(self.state.root),
+            leaves: self.state.occupied,
+        }
+        .write(&mut writer)?;
+
+        let mut written = 0;
+        let mut write = |index: usize, leaf: H::Fr| -> PmtreeResult<()> {
+            written += 1;
+            write_leaf(&mut writer, index, &H::serialize(leaf))
         };
 
-        // Load cache vec
-        let mut cache = vec![H::default_leaf(); depth + 1];
-        cache[depth] = H::default_leaf();
-        for i in (0..depth).rev() {
-            cache[i] = H::hash(&[cache[i + 1], cache[i + 1]]);
+        match self.iter_set_leaves() {
+            Ok(leaves) => {
+                for entry in leaves {
+                    let (index, leaf) = entry?;
+                    write(index, leaf)?;
+                }
+            }
+            // Without range scans the leaves are read in batches
+            Err(PmtreeErrorKind::DatabaseError(DatabaseErrorKind::UnsupportedOperation(_))) => {
+                for start in (0..self.state.next_index).step_by(EXPORT_BATCH) {
+                    let end = min(start + EXPORT_BATCH, self.state.next_index);
+                    let keys = self.state.leaf_keys(start..end);
+                    for (key, leaf) in keys.iter().zip(self.read_nodes(&keys)?) {
+                        if leaf != *self.state.default_leaf() {
+                            write(key.1, leaf)?;
+                        }
+                    }
+                }
+            }
+            Err(e) => return Err(e),
         }
 
-        Ok(Self {
-            db,
-            depth,
-            next_index,
-            cache,
-            root,
-        })
+        // The header announces the stored number of non-default leaves, which is inconsistent
+        if written != self.state.occupied {
+            return Err(PmtreeErrorKind::DatabaseError(
+                DatabaseErrorKind::MalformedMetadata("occupied_count"),
+            ));
+        }
+
+        writer
+            .flush()
+            .map_err(|e| snapshot_error(SnapshotErrorKind::Io(e)))
     }
 
     /// Closes the db connection
@@ -130,83 +744,160 @@ where
         self.db.close()
     }
 
+    /// Returns the db
+    pub fn db(&self) -> &D {
+        &self.db
+    }
+
+    /// Returns the mutable db. Writing tree keys directly breaks the tree
+    pub fn db_mut(&mut self) -> &mut D {
+        &mut self.db
+    }
+
+    /// Consumes the tree, returning the db
+    pub fn into_inner(self) -> D {
+        self.db
+    }
+
+    // Moves the tree to the db produced from its current one
+    pub(crate) fn map_db<E: Database>(self, f: impl FnOnce(D) -> E) -> MerkleTree<E, H> {
+        MerkleTree {
+            db: f(self.db),
+            state: self.state,
+        }
+    }
+
+    pub(crate) fn state(&self) -> &TreeState<H> {
+        &self.state
+    }
+
     /// Sets a leaf at the specified tree index
     pub fn set(&mut self, key: usize, leaf: H::Fr) -> PmtreeResult<()> {
-        if key >= self.capacity() {
-            return Err(PmtreeErrorKind::TreeError(TreeErrorKind::IndexOutOfBounds));
-        }
+        // Read the path siblings and the replaced leaf in one batch
+        let keys = self.state.set_keys(key)?;
+        let siblings = self.get_elems(&keys)?;
 
-        self.db
-            .put(Key(self.depth, key).into(), H::serialize(leaf))?;
-        self.recalculate_from(key)?;
+        let (root, nodes, old_leaf) = self.state.set(key, leaf, siblings);
 
-        // Update next_index in memory
-        self.next_index = max(self.next_index, key + 1);
+        self.commit(root, nodes, key, &[old_leaf], &[leaf])
+    }
 
-        // Update next_index in db
-        let next_index_val = self.next_index.to_be_bytes().to_vec();
-        self.db.put(NEXT_INDEX_KEY, next_index_val)?;
+    // Writes the root and the nodes with the leaf index, the free list, the number of
+    // non-default leaves and next_index after the run of `old_leaves` starting at `start`
+    // is replaced by `leaves`
+    fn commit(
+        &mut self,
+        root: H::Fr,
+        nodes: Nodes<H>,
+        start: usize,
+        old_leaves: &[H::Fr],
+        leaves: &[H::Fr],
+    ) -> PmtreeResult<()> {
+        let mut update = self.state.nodes_update(root, nodes);
+        if let Some(leaf_index) = self.state.leaf_index_update(start, old_leaves, leaves) {
+            update.writes.extend(self.leaf_index_writes(leaf_index)?);
+        }
+        self.state
+            .commit_leaves(&mut update, start, old_leaves, leaves);
 
-        Ok(())
+        self.apply(update)
     }
 
-    // Recalculates `Merkle Tree` from the specified key
-    fn recalculate_from(&mut self, key: usize) -> PmtreeResult<()> {
-        let mut depth = self.depth;
-        let mut i = key;
+    // Stores the writes of the update, the in-memory state changes only once they succeed
+    fn apply(&mut self, mut update: Update<H>) -> PmtreeResult<()> {
+        self.write(mem::take(&mut update.writes))?;
+        self.state.apply(update);
 
-        loop {
-            let value = self.hash_couple(depth, i)?;
-            i >>= 1;
-            depth -= 1;
-            self.db.put(Key(depth, i).into(), H::serialize(value))?;
+        Ok(())
+    }
 
-            if depth == 0 {
-                self.root = value;
-                break;
-            }
+    // Applies the writes to the db
+    fn write(&mut self, writes: Writes) -> PmtreeResult<()> {
+        if !writes.puts.is_empty() {
+            self.db.put_batch(writes.puts)?;
+        }
+        if !writes.deletes.is_empty() {
+            self.db.delete_batch(writes.deletes)?;
         }
 
         Ok(())
     }
 
-    // Hashes the correct couple for the key
-    fn hash_couple(&self, depth: usize, key: usize) -> PmtreeResult<H::Fr> {
-        let b = key & !1;
-        Ok(H::hash(&[
-            self.get_elem(Key(depth, b))?,
-            self.get_elem(Key(depth, b + 1))?,
-        ]))
+    /// Returns the node by the key, failing if the key is outside of the tree
+    pub fn get_elem(&self, key: Key) -> PmtreeResult<H::Fr> {
+        self.state.check_node(key.0, key.1)?;
+
+        Ok(self.get_elems(&[key])?[0])
     }
 
-    // Returns elem by the key
-    pub fn get_elem(&self, key: Key) -> PmtreeResult<H::Fr> {
-        let res = self
-            .db
-            .get(key.into())?
-            .map_or(self.cache[key.0], |value| H::deserialize(value));
+    // Returns elems by the keys, the ones missing in the node cache are read in one db batch
+    fn get_elems(&self, keys: &[Key]) -> PmtreeResult<Vec<H::Fr>> {
+        let (cached, missing) = self.state.cached_nodes(keys);
+        let read = if missing.is_empty() {
+            Vec::new()
+        } else {
+            self.read_nodes(&missing)?
+        };
+
+        Ok(self.state.fill_nodes(cached, &missing, read))
+    }
+
+    // Reads the nodes from the db in one batch, bypassing the node cache
+    fn read_nodes(&self, keys: &[Key]) -> PmtreeResult<Vec<H::Fr>> {
+        self.state.decode_nodes(keys, self.fetch_nodes(keys)?)
+    }
 
-        Ok(res)
+    // Reads the stored values of the nodes in one batch
+    fn fetch_nodes(&self, keys: &[Key]) -> PmtreeResult<Vec<Option<Value>>> {
+        check_batch(keys.len(), self.db.get_batch(&self.state.db_keys(keys))?)
     }
 
     /// Deletes a leaf at the `key` by setting it to its default value
     pub fn delete(&mut self, key: usize) -> PmtreeResult<()> {
-        if key >= self.next_index {
+        if key >= self.state.next_index {
             return Err(PmtreeErrorKind::TreeError(TreeErrorKind::InvalidKey));
         }
 
-        self.set(key, H::default_leaf())?;
+        self.set(key, *self.state.default_leaf())?;
 
         Ok(())
     }
 
     /// Inserts a leaf to the next available index
     pub fn update_next(&mut self, leaf: H::Fr) -> PmtreeResult<()> {
-        self.set(self.next_index, leaf)?;
+        self.set(self.state.next_index, leaf)?;
 
         Ok(())
     }
 
+    /// Inserts a leaf to the lowest freed index if the free list is enabled and not empty,
+    /// to the next available index otherwise. Returns the index of the leaf
+    pub fn insert(&mut self, leaf: H::Fr) -> PmtreeResult<usize> {
+        let index = self
+            .state
+            .free_list
+            .as_ref()
+            .and_then(FreeList::first)
+            .unwrap_or(self.state.next_index);
+
+        self.set(index, leaf)?;
+
+        Ok(index)
+    }
+
+    /// Enables the free list: indexes of the default leaves below next_index are kept
+    /// in the db as ranges and reused by `insert`. The already deleted leaves are collected right away
+    pub fn enable_free_list(&mut self) -> PmtreeResult<()> {
+        if self.state.free_list.is_some() {
+            return Ok(());
+        }
+
+        let leaves = self.read_nodes(&self.state.leaf_keys(0..self.state.next_index))?;
+        let update = self.state.enable_free_list(&leaves);
+
+        self.apply(update)
+    }
+
     /// Batch insertion from starting index
     pub fn set_range<I: IntoIterator<Item = H::Fr>>(
         &mut self,
@@ -221,142 +912,283 @@ where
 
     /// Batch insertion, updates the tree in parallel.
     pub fn batch_insert(&mut self, start: Option<usize>, leaves: &[H::Fr]) -> PmtreeResult<()> {
-        let start = start.unwrap_or(self.next_index);
-        let end = start + leaves.len();
+        let start = start.unwrap_or(self.state.next_index);
+        let keys = self.state.batch_keys(start, leaves.len())?;
 
-        if end > self.capacity() {
-            return Err(PmtreeErrorKind::TreeError(TreeErrorKind::MerkleTreeIsFull));
+        if leaves.is_empty() {
+            return Ok(());
         }
 
-        let mut subtree = HashMap::<Key, H::Fr>::new();
-
-        let root_key = Key(0, 0);
+        // Read the replaced leaves in the same batch as the edges
+        let values = self.get_elems(&keys)?;
+        let (recalculation, old_leaves) =
+            self.state
+                .recalculation(start, leaves.to_vec(), keys, values);
 
-        subtree.insert(root_key, self.root);
-        self.fill_nodes(root_key, start, end, &mut subtree, leaves, start)?;
+        let (root, nodes) = recalculation.run();
 
-        let subtree = Arc::new(RwLock::new(subtree));
+        self.commit(root, nodes, start, &old_leaves, leaves)
+    }
 
-        let root_val = rayon::ThreadPoolBuilder::new()
-            .num_threads(rayon::current_num_threads())
-            .build()
-            .unwrap()
-            .install(|| Self::batch_recalculate(root_key, Arc::clone(&subtree), self.depth));
+    /// Enables the leaf-to-index secondary index, kept in the same db and maintained by
+    /// `set`, `batch_insert` and `delete`. The already set leaves are indexed right away
+    pub fn enable_leaf_index(&mut self) -> PmtreeResult<()> {
+        if self.state.leaf_index {
+            return Ok(());
+        }
 
-        let subtree = RwLock::into_inner(Arc::try_unwrap(subtree).unwrap()).unwrap();
+        let leaves = self.read_nodes(&self.state.leaf_keys(0..self.state.next_index))?;
+        let mut update = self.state.enable_leaf_index();
+        update
+            .writes
+            .extend(self.leaf_index_writes(self.state.index_leaves(&leaves))?);
 
-        self.db.put_batch(
-            subtree
-                .into_iter()
-                .map(|(key, value)| (key.into(), H::serialize(value)))
-                .collect(),
-        )?;
+        self.apply(update)
+    }
 
-        // Update next_index value in db
-        if end > self.next_index {
-            self.next_index = end;
-            self.db
-                .put(NEXT_INDEX_KEY, self.next_index.to_be_bytes().to_vec())?;
+    // Reads the buckets touched by the update in one batch, returning their writes
+    fn leaf_index_writes(&self, update: LeafIndexUpdate) -> PmtreeResult<Writes> {
+        let keys = update.bucket_keys();
+        if keys.is_empty() {
+            return Ok(Writes::default());
         }
 
-        // Update root value in memory
-        self.root = root_val;
+        let values = check_batch(keys.len(), self.db.get_batch(&keys)?)?;
+        update.apply(keys, values)
+    }
 
-        Ok(())
+    /// Returns the lowest index holding the leaf, requires the leaf index to be enabled.
+    /// Default leaves aren't indexed
+    pub fn index_of(&self, leaf: &H::Fr) -> PmtreeResult<Option<usize>> {
+        let bucket = self.db.get(self.state.bucket_key(leaf)?)?;
+
+        self.state.find_leaf(leaf, bucket)
     }
 
-    // Fills hashmap subtree
-    fn fill_nodes(
-        &self,
-        key: Key,
-        start: usize,
-        end: usize,
-        subtree: &mut HashMap<Key, H::Fr>,
-        leaves: &[H::Fr],
-        from: usize,
-    ) -> PmtreeResult<()> {
-        if key.0 == self.depth {
-            if key.1 >= from {
-                subtree.insert(key, leaves[key.1 - from]);
+    /// Computes a Merkle proof for the lowest index holding the leaf,
+    /// requires the leaf index to be enabled
+    pub fn proof_for_leaf(&self, leaf: &H::Fr) -> PmtreeResult<Option<MerkleProof<H>>> {
+        self.index_of(leaf)?
+            .map(|index| self.proof(index))
+            .transpose()
+    }
+
+    /// Returns whether the leaf index is enabled
+    pub fn has_leaf_index(&self) -> bool {
+        self.state.leaf_index
+    }
+
+    /// Checks that every internal node covering the set leaves matches the hash of its children,
+    /// that the stored root and next_index match the in-memory ones and that no leaves are
+    /// stored beyond next_index. If the db supports range scans, every stored node is checked,
+    /// including the ones outside of the set leaves
+    pub fn verify_integrity(&self) -> PmtreeResult<IntegrityReport> {
+        let mut report = IntegrityReport::default();
+        let depth = self.state.depth;
+
+        // Indexes of the nodes to check on every level
+        let mut levels: Vec<BTreeSet<usize>> = (0..=depth)
+            .map(|level| (0..self.covered(level)).collect())
+            .collect();
+        if let Some(stored) = self.stored_nodes()? {
+            for (key, _) in stored {
+                if key.0 == depth && key.1 >= self.state.next_index {
+                    report.stray_leaves.push(key.1);
+                }
+                levels[key.0].insert(key.1);
             }
-            return Ok(());
         }
 
-        let left = Key(key.0 + 1, key.1 * 2);
-        let right = Key(key.0 + 1, key.1 * 2 + 1);
+        let leaves: Vec<Key> = levels[depth].iter().map(|&i| Key(depth, i)).collect();
+        self.read_checked(&leaves, &mut report.corrupted_nodes)?;
+
+        // Every level is read in one batch with the children of its nodes
+        for level in (0..depth).rev() {
+            let parents: Vec<Key> = levels[level].iter().map(|&i| Key(level, i)).collect();
+            let children: Vec<Key> = parents
+                .iter()
+                .flat_map(|key| [Key(level + 1, 2 * key.1), Key(level + 1, 2 * key.1 + 1)])
+                .collect();
+
+            let values = self.read_checked(
+                &[parents.as_slice(), &children].concat(),
+                &mut report.corrupted_nodes,
+            )?;
+            let (parent_values, child_values) = values.split_at(parents.len());
+
+            for (i, (&key, parent)) in parents.iter().zip(parent_values).enumerate() {
+                if let (Some(parent), Some(left), Some(right)) =
+                    (parent, child_values[2 * i], child_values[2 * i + 1])
+                {
+                    if hash_children(&*self.state.hasher, depth, level, left, right) != *parent {
+                        report.mismatched_nodes.push(key);
+                    }
+                }
+            }
+        }
 
-        let left_val = self.get_elem(left)?;
-        let right_val = self.get_elem(right)?;
+        let root = self.read_checked(&[Key(0, 0)], &mut report.corrupted_nodes)?;
+        report.root_mismatch = root[0] != Some(self.state.root);
 
-        subtree.insert(left, left_val);
-        subtree.insert(right, right_val);
+        report
+            .corrupted_nodes
+            .sort_unstable_by_key(|key| (key.0, key.1));
+        report.corrupted_nodes.dedup();
 
-        let half = 1 << (self.depth - key.0 - 1);
+        let next_index_key = metadata_key(self.state.tree_id, NEXT_INDEX);
+        report.next_index_mismatch = !report.stray_leaves.is_empty()
+            || match read_metadata(&self.db, next_index_key, "next_index") {
+                Ok(next_index) => next_index != self.state.next_index,
+                Err(PmtreeErrorKind::DatabaseError(_)) => true,
+                Err(e) => return Err(e),
+            };
 
-        if start < half {
-            self.fill_nodes(left, start, min(end, half), subtree, leaves, from)?;
-        }
+        Ok(report)
+    }
+
+    // Returns the number of nodes of the level covering the leaves below next_index, at least one
+    fn covered(&self, level: usize) -> usize {
+        max(
+            self.state
+                .next_index
+                .div_ceil(1 << (self.state.depth - level)),
+            1,
+        )
+    }
 
-        if end > half {
-            self.fill_nodes(right, 0, end - half, subtree, leaves, from)?;
+    // Returns all the stored nodes of the tree, `None` if the db doesn't support range scans
+    fn stored_nodes(&self) -> PmtreeResult<Option<Vec<(Key, Value)>>> {
+        let from = Key(0, 0).to_db_key(self.state.tree_id);
+        let to = Key(self.state.depth + 1, 0).to_db_key(self.state.tree_id);
+
+        match self.db.iter_range(from, to) {
+            Ok(entries) => entries
+                .map(|entry| entry.map(|(key, value)| (Key::from_db_key(&key), value)))
+                .collect::<PmtreeResult<Vec<_>>>()
+                .map(Some),
+            Err(PmtreeErrorKind::DatabaseError(DatabaseErrorKind::UnsupportedOperation(_))) => {
+                Ok(None)
+            }
+            Err(e) => Err(e),
         }
+    }
 
-        Ok(())
+    // Reads the nodes in one batch, bypassing the node cache.
+    // Nodes that can't be deserialized are recorded as corrupted and returned as `None`
+    fn read_checked(
+        &self,
+        keys: &[Key],
+        corrupted: &mut Vec<Key>,
+    ) -> PmtreeResult<Vec<Option<H::Fr>>> {
+        Ok(keys
+            .iter()
+            .zip(self.fetch_nodes(keys)?)
+            .map(|(&key, value)| match value {
+                Some(value) => H::deserialize(value).map_err(|_| corrupted.push(key)).ok(),
+                None => Some(self.state.cache[key.0]),
+            })
+            .collect())
     }
 
-    // Recalculates tree in parallel (in-memory)
-    fn batch_recalculate(
-        key: Key,
-        subtree: Arc<RwLock<HashMap<Key, H::Fr>>>,
-        depth: usize,
-    ) -> H::Fr {
-        let left_child = Key(key.0 + 1, key.1 * 2);
-        let right_child = Key(key.0 + 1, key.1 * 2 + 1);
+    /// Recomputes all internal nodes in parallel from the leaves below next_index.
+    /// If the db supports range scans, all other stored internal nodes and the leaves stored
+    /// beyond next_index, left by an interrupted update, are discarded.
+    /// The number of non-default leaves and the free list are recomputed as well
+    pub fn rebuild_internal_nodes(&mut self) -> PmtreeResult<()> {
+        let depth = self.state.depth;
+        let next_index = self.state.next_index;
+        let leaves = self.read_nodes(&self.state.leaf_keys(0..next_index))?;
+
+        let (root, mut nodes) = if leaves.is_empty() {
+            (self.state.cache[0], vec![(Key(0, 0), self.state.cache[0])])
+        } else {
+            // Nodes right of the set leaves hold default values
+            let keys = self.state.batch_keys(0, next_index)?;
+            let values = keys.iter().map(|key| self.state.cache[key.0]).collect();
+            let (recalculation, _) = self.state.recalculation(0, leaves.clone(), keys, values);
+            recalculation.run()
+        };
 
-        if key.0 == depth || !subtree.read().unwrap().contains_key(&left_child) {
-            return *subtree.read().unwrap().get(&key).unwrap();
+        nodes.retain(|(key, _)| key.0 != depth);
+
+        // Stale nodes are reset to their default values, i.e. deleted
+        let mut stray_leaves = LeafIndexUpdate::new(self.state.tree_id);
+        if let Some(stored) = self.stored_nodes()? {
+            let rebuilt: HashSet<Key> = nodes.iter().map(|&(key, _)| key).collect();
+            for (key, value) in stored {
+                if key.0 == depth {
+                    if key.1 >= next_index {
+                        stray_leaves.remove(value, key.1);
+                        nodes.push((key, self.state.cache[depth]));
+                    }
+                } else if !rebuilt.contains(&key) {
+                    nodes.push((key, self.state.cache[key.0]));
+                }
+            }
         }
 
-        let (left, right) = rayon::join(
-            || Self::batch_recalculate(left_child, Arc::clone(&subtree), depth),
-            || Self::batch_recalculate(right_child, Arc::clone(&subtree), depth),
-        );
-
-        let result = H::hash(&[left, right]);
+        let mut update = self.state.nodes_update(root, nodes);
+        if self.state.leaf_index {
+            update.writes.extend(self.leaf_index_writes(stray_leaves)?);
+        }
 
-        subtree.write().unwrap().insert(key, result);
+        // Restore next_index, the number of non-default leaves and the free list in db
+        self.state.recount(&mut update, &leaves);
 
-        result
+        self.apply(update)
     }
 
     /// Computes a Merkle proof for the leaf at the specified index
     pub fn proof(&self, index: usize) -> PmtreeResult<MerkleProof<H>> {
-        if index >= self.capacity() {
-            return Err(PmtreeErrorKind::TreeError(TreeErrorKind::IndexOutOfBounds));
-        }
+        let keys = self.state.proof_keys(index)?;
+        let siblings = self.get_elems(&keys)?;
 
-        let mut witness = Vec::with_capacity(self.depth);
+        Ok(self.state.proof(&keys, siblings))
+    }
 
-        let mut i = index;
-        let mut depth = self.depth;
-        while depth != 0 {
-            i ^= 1;
-            witness.push((
-                self.get_elem(Key(depth, i))?,
-                (1 - (i & 1)).try_into().unwrap(),
-            ));
-            i >>= 1;
-            depth -= 1;
-        }
+    /// Verifies a Merkle proof with respect to the input leaf and the tree root,
+    /// rejecting proofs whose length differs from the tree depth
+    pub fn verify(&self, leaf: &H::Fr, witness: &MerkleProof<H>) -> bool {
+        self.state.verify(leaf, witness)
+    }
 
-        Ok(MerkleProof(witness))
+    /// Returns the stored node at the level (root is level 0) and the index within the level
+    pub fn node(&self, level: usize, index: usize) -> PmtreeResult<H::Fr> {
+        self.get_elem(Key(level, index))
     }
 
-    /// Verifies a Merkle proof with respect to the input leaf and the tree root
-    pub fn verify(&self, leaf: &H::Fr, witness: &MerkleProof<H>) -> bool {
-        let expected_root = witness.compute_root_from(leaf);
+    /// Returns the root of the subtree under the node at the level and the index,
+    /// i.e. the value the node is combined with its sibling as (leaves go through `hash_leaf`)
+    pub fn subtree_root(&self, level: usize, index: usize) -> PmtreeResult<H::Fr> {
+        let node = self.node(level, index)?;
+
+        Ok(if level == self.state.depth {
+            self.state.hasher.hash_leaf(node)
+        } else {
+            node
+        })
+    }
 
-        self.root() == expected_root
+    /// Returns the Merkle proof of the subtree root at the level and the index against the tree root.
+    /// The proof has `level` elements, see `MerkleProof::compute_root_from_subtree`
+    pub fn subtree_proof(&self, level: usize, index: usize) -> PmtreeResult<MerkleProof<H>> {
+        self.state.check_node(level, index)?;
+
+        let keys = path_siblings(level, index);
+        let siblings = self.get_elems(&keys)?;
+
+        Ok(self.state.proof(&keys, siblings))
+    }
+
+    /// Verifies a subtree proof with respect to the subtree root at the level and the tree root
+    pub fn verify_subtree(
+        &self,
+        level: usize,
+        subtree_root: &H::Fr,
+        witness: &MerkleProof<H>,
+    ) -> bool {
+        witness.length() == level
+            && witness.compute_root_from_subtree(&self.state.hasher, subtree_root) == self.root()
     }
 
     /// Returns the leaf by the key
@@ -365,45 +1197,203 @@ where
             return Err(PmtreeErrorKind::TreeError(TreeErrorKind::IndexOutOfBounds));
         }
 
-        self.get_elem(Key(self.depth, key))
+        self.get_elem(Key(self.state.depth, key))
+    }
+
+    /// Returns the stored leaves with indexes in the range, ordered by index.
+    /// Default leaves aren't stored, so they are skipped.
+    /// Requires `Database::iter_range` support
+    pub fn leaves(
+        &self,
+        range: Range<usize>,
+    ) -> PmtreeResult<impl Iterator<Item = PmtreeResult<(usize, H::Fr)>> + '_> {
+        if range.start > range.end || range.end > self.capacity() {
+            return Err(PmtreeErrorKind::TreeError(TreeErrorKind::IndexOutOfBounds));
+        }
+
+        // Capacity is bounded by the first key of the next level, it doesn't fit at MAX_DEPTH
+        let bound = |index: usize| {
+            if index == self.capacity() {
+                Key(self.state.depth + 1, 0)
+            } else {
+                Key(self.state.depth, index)
+            }
+            .to_db_key(self.state.tree_id)
+        };
+        let (from, to) = (bound(range.start), bound(range.end));
+
+        Ok(self.db.iter_range(from, to)?.map(|entry| {
+            let (db_key, value) = entry?;
+            let key = Key::from_db_key(&db_key);
+            Ok((key.1, deserialize_node::<H>(key, value)?))
+        }))
+    }
+
+    /// Returns all the set (non-default) leaves, ordered by index.
+    /// Requires `Database::iter_range` support
+    pub fn iter_set_leaves(
+        &self,
+    ) -> PmtreeResult<impl Iterator<Item = PmtreeResult<(usize, H::Fr)>> + '_> {
+        self.leaves(0..self.state.next_index)
     }
 
     /// Returns the root of the tree
     pub fn root(&self) -> H::Fr {
-        self.root
+        self.state.root
     }
 
-    /// Returns the total number of leaves set
+    /// Returns next_index, i.e. the number of leaves up to the highest set one.
+    /// See `occupied_count` for the number of non-default leaves
     pub fn leaves_set(&self) -> usize {
-        self.next_index
+        self.state.next_index
+    }
+
+    /// Returns the ranges of freed indexes below next_index, in ascending order.
+    /// Empty if the free list isn't enabled
+    pub fn free_slots(&self) -> Vec<Range<usize>> {
+        self.state
+            .free_list
+            .iter()
+            .flat_map(FreeList::ranges)
+            .collect()
+    }
+
+    /// Returns the number of leaves that differ from the default leaf
+    /// (`H::default_leaf()`, unless `Hasher::zero_values` supplies another leaf zero value)
+    pub fn occupied_count(&self) -> usize {
+        self.state.occupied
     }
 
     /// Returns the capacity of the tree, i.e. the maximum number of leaves
     pub fn capacity(&self) -> usize {
-        1 << self.depth
+        self.state.capacity()
+    }
+
+    /// Returns the id of the tree inside the db
+    pub fn tree_id(&self) -> TreeId {
+        self.state.tree_id
     }
 
     /// Returns the depth of the tree
     pub fn depth(&self) -> usize {
-        self.depth
+        self.state.depth
+    }
+
+    /// Sets the thread pool used by batch operations (the global rayon pool by default)
+    pub fn set_thread_pool(&mut self, pool: Arc<rayon::ThreadPool>) {
+        self.state.thread_pool = Some(pool);
+    }
+
+    /// Builds a dedicated thread pool with the specified number of threads for batch operations
+    pub fn set_num_threads(&mut self, num_threads: usize) -> PmtreeResult<()> {
+        self.state.set_num_threads(num_threads)
+    }
+
+    /// Sets the minimal number of leaves for which batch operations run in parallel
+    pub fn set_parallel_threshold(&mut self, threshold: usize) {
+        self.state.parallel_threshold = threshold;
+    }
+
+    /// Enables the in-memory node cache: nodes of the top `pinned_levels` levels are kept
+    /// permanently, other nodes are kept in an LRU of `capacity` entries.
+    /// Writes go through the cache, so it never serves stale nodes
+    pub fn set_node_cache(&mut self, pinned_levels: usize, capacity: usize) {
+        self.state.set_node_cache(pinned_levels, capacity);
+    }
+
+    /// Returns the node cache statistics, if the cache is enabled
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.state.cache_stats()
+    }
+
+    /// Returns the header describing the tree
+    pub fn header(&self) -> TreeHeader {
+        TreeHeader::new::<H>(self.state.depth)
+    }
+
+    /// Returns the hasher instance used by the tree
+    pub fn hasher(&self) -> &H {
+        &self.state.hasher
     }
 }
 
 impl<H: Hasher> MerkleProof<H> {
     /// Computes the Merkle root by iteratively hashing specified Merkle proof with specified leaf
-    pub fn compute_root_from(&self, leaf: &H::Fr) -> H::Fr {
-        let mut acc = *leaf;
-        for w in self.0.iter() {
+    pub fn compute_root_from(&self, leaf: &H::Fr) -> H::Fr
+    where
+        H: Default,
+    {
+        self.compute_root_with_hasher(&H::default(), leaf)
+    }
+
+    /// Computes the Merkle root from specified leaf, hashing through the specified hasher instance
+    pub fn compute_root_with_hasher(&self, hasher: &H, leaf: &H::Fr) -> H::Fr {
+        self.compute_root_from_subtree(hasher, &hasher.hash_leaf(*leaf))
+    }
+
+    /// Computes the Merkle root from the root of the subtree the proof starts at,
+    /// which isn't hashed with `hash_leaf` (see `MerkleTree::subtree_proof`)
+    pub fn compute_root_from_subtree(&self, hasher: &H, subtree_root: &H::Fr) -> H::Fr {
+        let mut acc = *subtree_root;
+        for (i, w) in self.0.iter().enumerate() {
+            let level = self.0.len() - i - 1;
             if w.1 == 0 {
-                acc = H::hash(&[acc, w.0]);
+                acc = hasher.hash_node(level, &[acc, w.0]);
             } else {
-                acc = H::hash(&[w.0, acc]);
+                acc = hasher.hash_node(level, &[w.0, acc]);
             }
         }
 
         acc
     }
 
+    /// Verifies the proof with respect to the input leaf and the root
+    pub fn verify(&self, leaf: &H::Fr, root: &H::Fr) -> bool
+    where
+        H: Default,
+    {
+        self.verify_with_hasher(&H::default(), leaf, root)
+    }
+
+    /// Verifies the proof with respect to the input leaf and the root,
+    /// hashing through the specified hasher instance
+    pub fn verify_with_hasher(&self, hasher: &H, leaf: &H::Fr, root: &H::Fr) -> bool {
+        self.compute_root_with_hasher(hasher, leaf) == *root
+    }
+
+    /// Verifies the proof with respect to the input leaf and the root,
+    /// also checking that it's a proof for the leaf at the index
+    pub fn verify_at_index(&self, leaf: &H::Fr, index: usize, root: &H::Fr) -> bool
+    where
+        H: Default,
+    {
+        self.verify_at_index_with_hasher(&H::default(), leaf, index, root)
+    }
+
+    /// Verifies the proof with respect to the input leaf at the index and the root,
+    /// hashing through the specified hasher instance
+    pub fn verify_at_index_with_hasher(
+        &self,
+        hasher: &H,
+        leaf: &H::Fr,
+        index: usize,
+        root: &H::Fr,
+    ) -> bool {
+        self.leaf_index() == index && self.verify_with_hasher(hasher, leaf, root)
+    }
+
+    /// Checks that the proof is a proof for a tree of the expected depth
+    pub fn check_depth(&self, depth: usize) -> PmtreeResult<()> {
+        if self.length() != depth {
+            return Err(PmtreeErrorKind::TreeError(TreeErrorKind::DepthMismatch {
+                expected: depth,
+                actual: self.length(),
+            }));
+        }
+
+        Ok(())
+    }
+
     /// Computes the leaf index corresponding to a Merkle proof
     pub fn leaf_index(&self) -> usize {
         self.get_path_index()
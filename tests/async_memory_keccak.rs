@@ -0,0 +1,366 @@
+#![cfg(feature = "async")]
+
+use hex_literal::hex;
+use pmtree::*;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tiny_keccak::{Hasher as _, Keccak};
+
+#[derive(Default)]
+struct MyKeccak;
+
+#[derive(Default)]
+struct MemoryDBConfig;
+
+struct AsyncMemoryDB(HashMap<DBKey, Value>);
+
+impl AsyncDatabase for AsyncMemoryDB {
+    type Config = MemoryDBConfig;
+
+    async fn new(_db_config: MemoryDBConfig) -> PmtreeResult<Self> {
+        Ok(AsyncMemoryDB(HashMap::new()))
+    }
+
+    async fn load(_db_config: MemoryDBConfig) -> PmtreeResult<Self> {
+        Err(PmtreeErrorKind::DatabaseError(
+            DatabaseErrorKind::CannotLoadDatabase,
+        ))
+    }
+
+    async fn get(&self, key: DBKey) -> PmtreeResult<Option<Value>> {
+        Ok(self.0.get(&key).cloned())
+    }
+
+    async fn put(&mut self, key: DBKey, value: Value) -> PmtreeResult<()> {
+        self.0.insert(key, value);
+
+        Ok(())
+    }
+
+    async fn put_batch(&mut self, subtree: HashMap<DBKey, Value>) -> PmtreeResult<()> {
+        self.0.extend(subtree);
+
+        Ok(())
+    }
+
+    async fn delete(&mut self, key: DBKey) -> PmtreeResult<()> {
+        self.0.remove(&key);
+
+        Ok(())
+    }
+
+    async fn close(&mut self) -> PmtreeResult<()> {
+        Ok(())
+    }
+}
+
+struct MemoryDB(HashMap<DBKey, Value>);
+
+impl Database for MemoryDB {
+    type Config = MemoryDBConfig;
+
+    fn new(_db_config: MemoryDBConfig) -> PmtreeResult<Self> {
+        Ok(MemoryDB(HashMap::new()))
+    }
+
+    fn load(_db_config: MemoryDBConfig) -> PmtreeResult<Self> {
+        Err(PmtreeErrorKind::DatabaseError(
+            DatabaseErrorKind::CannotLoadDatabase,
+        ))
+    }
+
+    fn get(&self, key: DBKey) -> PmtreeResult<Option<Value>> {
+        Ok(self.0.get(&key).cloned())
+    }
+
+    fn put(&mut self, key: DBKey, value: Value) -> PmtreeResult<()> {
+        self.0.insert(key, value);
+
+        Ok(())
+    }
+
+    fn put_batch(&mut self, subtree: HashMap<DBKey, Value>) -> PmtreeResult<()> {
+        self.0.extend(subtree);
+
+        Ok(())
+    }
+
+    fn delete(&mut self, key: DBKey) -> PmtreeResult<()> {
+        self.0.remove(&key);
+
+        Ok(())
+    }
+
+    fn close(&mut self) -> PmtreeResult<()> {
+        Ok(())
+    }
+}
+
+impl Hasher for MyKeccak {
+    type Fr = [u8; 32];
+
+    const ID: &'static str = "keccak";
+
+    fn default_leaf() -> Self::Fr {
+        [0; 32]
+    }
+
+    fn serialize(value: Self::Fr) -> Value {
+        value.to_vec()
+    }
+
+    fn deserialize(value: Value) -> PmtreeResult<Self::Fr> {
+        value
+            .try_into()
+            .map_err(|_| PmtreeErrorKind::CustomError(String::from("Invalid value length")))
+    }
+
+    fn hash(&self, input: &[Self::Fr]) -> Self::Fr {
+        let mut output = [0; 32];
+        let mut hasher = Keccak::v256();
+        for element in input {
+            hasher.update(element);
+        }
+        hasher.finalize(&mut output);
+        output
+    }
+}
+
+const LEAVES: [[u8; 32]; 4] = [
+    hex!("0000000000000000000000000000000000000000000000000000000000000001"),
+    hex!("0000000000000000000000000000000000000000000000000000000000000002"),
+    hex!("0000000000000000000000000000000000000000000000000000000000000003"),
+    hex!("0000000000000000000000000000000000000000000000000000000000000004"),
+];
+
+#[tokio::test]
+async fn async_insert_delete() -> PmtreeResult<()> {
+    let mut mt = AsyncMerkleTree::<AsyncMemoryDB, MyKeccak>::new(2, MemoryDBConfig).await?;
+
+    let default_tree_root =
+        hex!("b4c11951957c6f8f642c4af61cd6b24640fec6dc7fc607ee8206a99e92410d30");
+
+    assert_eq!(mt.root(), default_tree_root);
+
+    let roots = [
+        hex!("c1ba1812ff680ce84c1d5b4f1087eeb08147a4d510f3496b2849df3a73f5af95"),
+        hex!("893760ec5b5bee236f29e85aef64f17139c3c1b7ff24ce64eb6315fca0f2485b"),
+        hex!("222ff5e0b5877792c2bc1670e2ccd0c2c97cd7bb1672a57d598db05092d3d72c"),
+        hex!("a9bb8c3f1f12e9aa903a50c47f314b57610a3ab32f2d463293f58836def38d36"),
+    ];
+
+    for (leaf, root) in LEAVES.into_iter().zip(roots) {
+        mt.update_next(leaf).await?;
+        assert_eq!(mt.root(), root);
+    }
+
+    for (i, leaf) in LEAVES.iter().enumerate() {
+        assert_eq!(mt.get(i).await?, *leaf);
+        assert!(mt.verify(leaf, &mt.proof(i).await?));
+    }
+
+    for i in (0..LEAVES.len()).rev() {
+        mt.delete(i).await?;
+    }
+
+    assert_eq!(mt.root(), default_tree_root);
+    assert!(mt.update_next(LEAVES[0]).await.is_err());
+
+    // Only the metadata and the registry of trees are left
+    assert_eq!(mt.db().0.len(), 5);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn async_batch_insert_blocking_db() -> PmtreeResult<()> {
+    let mut mt =
+        AsyncMerkleTree::<BlockingDatabase<MemoryDB>, MyKeccak>::new(2, MemoryDBConfig).await?;
+
+    mt.batch_insert(None, &LEAVES[..3]).await?;
+    mt.set(3, LEAVES[3]).await?;
+
+    let root = hex!("a9bb8c3f1f12e9aa903a50c47f314b57610a3ab32f2d463293f58836def38d36");
+    assert_eq!(mt.root(), root);
+
+    // The stored tree can be loaded both asynchronously and synchronously
+    let db = mt.into_inner();
+    let shared: Arc<Mutex<MemoryDB>> = db.shared();
+
+    let mt = AsyncMerkleTree::<_, MyKeccak>::load_in(db, 0).await?;
+    assert_eq!(mt.root(), root);
+    assert_eq!(mt.leaves_set(), 4);
+    assert_eq!(mt.occupied_count(), 4);
+
+    let sync_mt = MerkleTree::<_, MyKeccak>::load_with_db(shared)?;
+    assert_eq!(sync_mt.root(), root);
+    assert_eq!(
+        sync_mt.proof(2)?.get_path_elements(),
+        mt.proof(2).await?.get_path_elements()
+    );
+
+    Ok(())
+}
+
+// Keccak with the leaf zero value supplied by `zero_values` instead of `default_leaf`
+#[derive(Default)]
+struct OnesKeccak;
+
+impl Hasher for OnesKeccak {
+    type Fr = [u8; 32];
+
+    const ID: &'static str = "keccak-ones";
+
+    fn serialize(value: Self::Fr) -> Value {
+        MyKeccak::serialize(value)
+    }
+
+    fn deserialize(value: Value) -> PmtreeResult<Self::Fr> {
+        MyKeccak::deserialize(value)
+    }
+
+    fn hash(&self, input: &[Self::Fr]) -> Self::Fr {
+        MyKeccak.hash(input)
+    }
+
+    fn zero_values(&self, depth: usize) -> Vec<Self::Fr> {
+        let mut zeros = vec![[0xff; 32]; depth + 1];
+        for level in (0..depth).rev() {
+            zeros[level] = self.hash(&[zeros[level + 1], zeros[level + 1]]);
+        }
+
+        zeros
+    }
+}
+
+#[tokio::test]
+async fn async_delete_custom_leaf_zero() -> PmtreeResult<()> {
+    let mut mt = AsyncMerkleTree::<AsyncMemoryDB, OnesKeccak>::new(2, MemoryDBConfig).await?;
+    let empty_root = mt.root();
+
+    mt.update_next(LEAVES[0]).await?;
+    mt.delete(0).await?;
+
+    assert_eq!(mt.root(), empty_root);
+    assert_eq!(mt.occupied_count(), 0);
+    assert_eq!(mt.get(0).await?, [0xff; 32]);
+    assert_eq!(mt.db().0.len(), 5);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn async_leaf_index_free_list() -> PmtreeResult<()> {
+    let mut mt =
+        AsyncMerkleTree::<BlockingDatabase<MemoryDB>, MyKeccak>::new(3, MemoryDBConfig).await?;
+    mt.set_node_cache(1, 4);
+    mt.set_num_threads(2)?;
+    mt.set_parallel_threshold(0);
+
+    mt.batch_insert(None, &LEAVES).await?;
+    mt.enable_leaf_index().await?;
+    mt.enable_free_list().await?;
+    mt.delete(1).await?;
+
+    assert_eq!(mt.index_of(&LEAVES[2]).await?, Some(2));
+    assert_eq!(mt.index_of(&LEAVES[1]).await?, None);
+    assert_eq!(mt.free_slots(), vec![1..2]);
+    assert_eq!(mt.insert(LEAVES[3]).await?, 1);
+    assert!(mt.free_slots().is_empty());
+    assert!(mt.cache_stats().unwrap().hits > 0);
+
+    // Both trees maintain the leaf index and the free list the same way
+    let mut sync_mt = MerkleTree::<MemoryDB, MyKeccak>::new(3, MemoryDBConfig)?;
+    sync_mt.set_range(0, [LEAVES[0], LEAVES[3], LEAVES[2], LEAVES[3]])?;
+    assert_eq!(mt.root(), sync_mt.root());
+
+    let db = mt.into_inner();
+    let loaded = MerkleTree::<_, MyKeccak>::load_with_db(db.shared())?;
+    assert!(loaded.has_leaf_index());
+    assert_eq!(loaded.index_of(&LEAVES[3])?, Some(1));
+    assert!(loaded.free_slots().is_empty());
+    assert!(loaded.verify_integrity()?.is_ok());
+
+    Ok(())
+}
+
+// Memory db whose batch writes fail on demand
+struct FailingAsyncDB {
+    db: AsyncMemoryDB,
+    fail: AtomicBool,
+}
+
+impl AsyncDatabase for FailingAsyncDB {
+    type Config = MemoryDBConfig;
+
+    async fn new(db_config: MemoryDBConfig) -> PmtreeResult<Self> {
+        Ok(FailingAsyncDB {
+            db: AsyncMemoryDB::new(db_config).await?,
+            fail: AtomicBool::new(false),
+        })
+    }
+
+    async fn load(db_config: MemoryDBConfig) -> PmtreeResult<Self> {
+        let db = AsyncMemoryDB::load(db_config).await?;
+
+        Ok(FailingAsyncDB {
+            db,
+            fail: AtomicBool::new(false),
+        })
+    }
+
+    async fn get(&self, key: DBKey) -> PmtreeResult<Option<Value>> {
+        self.db.get(key).await
+    }
+
+    async fn put(&mut self, key: DBKey, value: Value) -> PmtreeResult<()> {
+        self.db.put(key, value).await
+    }
+
+    async fn put_batch(&mut self, subtree: HashMap<DBKey, Value>) -> PmtreeResult<()> {
+        if self.fail.load(Ordering::Relaxed) {
+            return Err(PmtreeErrorKind::DatabaseError(
+                DatabaseErrorKind::CustomError(String::from("Write failed")),
+            ));
+        }
+
+        self.db.put_batch(subtree).await
+    }
+
+    async fn delete(&mut self, key: DBKey) -> PmtreeResult<()> {
+        self.db.delete(key).await
+    }
+
+    async fn close(&mut self) -> PmtreeResult<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn async_failed_writes() -> PmtreeResult<()> {
+    let mut mt = AsyncMerkleTree::<FailingAsyncDB, MyKeccak>::new(3, MemoryDBConfig).await?;
+    mt.set_node_cache(1, 16);
+    mt.enable_free_list().await?;
+
+    mt.batch_insert(None, &LEAVES[..2]).await?;
+    mt.delete(0).await?;
+    let root = mt.root();
+
+    // Failed updates leave the tree as it was stored
+    mt.db().fail.store(true, Ordering::Relaxed);
+    assert!(mt.batch_insert(None, &LEAVES[2..]).await.is_err());
+    assert!(mt.insert(LEAVES[3]).await.is_err());
+
+    assert_eq!(mt.root(), root);
+    assert_eq!(mt.leaves_set(), 2);
+    assert_eq!(mt.occupied_count(), 1);
+    assert_eq!(mt.free_slots(), vec![0..1]);
+    assert_eq!(mt.get(0).await?, [0; 32]);
+
+    mt.db().fail.store(false, Ordering::Relaxed);
+    assert_eq!(mt.insert(LEAVES[3]).await?, 0);
+    assert_eq!(mt.get(0).await?, LEAVES[3]);
+
+    Ok(())
+}
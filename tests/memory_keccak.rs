@@ -4,7 +4,8 @@ use std::collections::HashMap;
 use tiny_keccak::{Hasher as _, Keccak};
 
 struct MemoryDB(HashMap<DBKey, Value>);
-struct MyKeccak(Keccak);
+#[derive(Default)]
+struct MyKeccak;
 
 #[derive(Default)]
 struct MemoryDBConfig;
@@ -33,11 +34,29 @@ impl Database for MemoryDB {
     }
 
     fn put_batch(&mut self, subtree: HashMap<DBKey, Value>) -> PmtreeResult<()> {
-        self.0.extend(subtree.into_iter());
+        self.0.extend(subtree);
+
+        Ok(())
+    }
+
+    fn delete(&mut self, key: DBKey) -> PmtreeResult<()> {
+        self.0.remove(&key);
 
         Ok(())
     }
 
+    fn iter_range(&self, from: DBKey, to: DBKey) -> PmtreeResult<DBIterator<'_>> {
+        let mut entries: Vec<(DBKey, Value)> = self
+            .0
+            .iter()
+            .filter(|(key, _)| (from..to).contains(*key))
+            .map(|(&key, value)| (key, value.clone()))
+            .collect();
+        entries.sort();
+
+        Ok(Box::new(entries.into_iter().map(Ok)))
+    }
+
     fn close(&mut self) -> PmtreeResult<()> {
         Ok(())
     }
@@ -46,6 +65,8 @@ impl Database for MemoryDB {
 impl Hasher for MyKeccak {
     type Fr = [u8; 32];
 
+    const ID: &'static str = "keccak";
+
     fn default_leaf() -> Self::Fr {
         [0; 32]
     }
@@ -54,11 +75,13 @@ impl Hasher for MyKeccak {
         value.to_vec()
     }
 
-    fn deserialize(value: Value) -> Self::Fr {
-        value.try_into().unwrap()
+    fn deserialize(value: Value) -> PmtreeResult<Self::Fr> {
+        value
+            .try_into()
+            .map_err(|_| PmtreeErrorKind::CustomError(String::from("Invalid value length")))
     }
 
-    fn hash(input: &[Self::Fr]) -> Self::Fr {
+    fn hash(&self, input: &[Self::Fr]) -> Self::Fr {
         let mut output = [0; 32];
         let mut hasher = Keccak::v256();
         for element in input {
@@ -69,6 +92,17 @@ impl Hasher for MyKeccak {
     }
 }
 
+// Returns `n` distinct non-default leaves 1, 2, ..., n
+fn leaves(n: u8) -> Vec<[u8; 32]> {
+    (0..n)
+        .map(|i| {
+            let mut leaf = [0; 32];
+            leaf[31] = i + 1;
+            leaf
+        })
+        .collect()
+}
+
 #[test]
 fn insert_delete() -> PmtreeResult<()> {
     let mut mt = MerkleTree::<MemoryDB, MyKeccak>::new(2, MemoryDBConfig)?;
@@ -154,3 +188,1481 @@ fn set_range() -> PmtreeResult<()> {
 
     Ok(())
 }
+
+// RFC 6962-style hasher: leaves and internal nodes are domain-separated
+#[derive(Default)]
+struct PrefixedKeccak;
+
+impl Hasher for PrefixedKeccak {
+    type Fr = [u8; 32];
+
+    const ID: &'static str = "keccak-rfc6962";
+
+    fn serialize(value: Self::Fr) -> Value {
+        value.to_vec()
+    }
+
+    fn deserialize(value: Value) -> PmtreeResult<Self::Fr> {
+        value
+            .try_into()
+            .map_err(|_| PmtreeErrorKind::CustomError(String::from("Invalid value length")))
+    }
+
+    fn hash(&self, input: &[Self::Fr]) -> Self::Fr {
+        MyKeccak.hash(input)
+    }
+
+    fn hash_leaf(&self, leaf: Self::Fr) -> Self::Fr {
+        let mut output = [0; 32];
+        let mut hasher = Keccak::v256();
+        hasher.update(&[0x00]);
+        hasher.update(&leaf);
+        hasher.finalize(&mut output);
+        output
+    }
+
+    fn hash_node(&self, _level: usize, children: &[Self::Fr]) -> Self::Fr {
+        let mut output = [0; 32];
+        let mut hasher = Keccak::v256();
+        hasher.update(&[0x01]);
+        for child in children {
+            hasher.update(child);
+        }
+        hasher.finalize(&mut output);
+        output
+    }
+}
+
+#[test]
+fn domain_separated_hashing() -> PmtreeResult<()> {
+    let mut mt = MerkleTree::<MemoryDB, PrefixedKeccak>::new(2, MemoryDBConfig)?;
+    let mut batch_mt = MerkleTree::<MemoryDB, PrefixedKeccak>::new(2, MemoryDBConfig)?;
+
+    let leaves = [
+        hex!("0000000000000000000000000000000000000000000000000000000000000001"),
+        hex!("0000000000000000000000000000000000000000000000000000000000000002"),
+        hex!("0000000000000000000000000000000000000000000000000000000000000003"),
+    ];
+
+    for &leaf in leaves.iter() {
+        mt.update_next(leaf)?;
+    }
+    batch_mt.batch_insert(None, &leaves)?;
+
+    let leaf_hashes: Vec<_> = leaves
+        .iter()
+        .chain([PrefixedKeccak::default_leaf()].iter())
+        .map(|&leaf| PrefixedKeccak.hash_leaf(leaf))
+        .collect();
+    let expected_root = PrefixedKeccak.hash_node(
+        0,
+        &[
+            PrefixedKeccak.hash_node(1, &leaf_hashes[0..2]),
+            PrefixedKeccak.hash_node(1, &leaf_hashes[2..4]),
+        ],
+    );
+
+    assert_eq!(mt.root(), expected_root);
+    assert_eq!(batch_mt.root(), expected_root);
+
+    for (i, leaf) in leaves.iter().enumerate() {
+        assert!(mt.verify(leaf, &mt.proof(i)?));
+        assert!(!mt.verify(&PrefixedKeccak.hash_leaf(*leaf), &mt.proof(i)?));
+    }
+
+    Ok(())
+}
+
+// Hasher with custom precomputed zero values per level
+#[derive(Default)]
+struct ZeroLadderKeccak;
+
+impl ZeroLadderKeccak {
+    fn zero(level: usize) -> [u8; 32] {
+        let mut output = [0; 32];
+        let mut hasher = Keccak::v256();
+        hasher.update(b"tornado");
+        hasher.update(&level.to_be_bytes());
+        hasher.finalize(&mut output);
+        output
+    }
+}
+
+impl Hasher for ZeroLadderKeccak {
+    type Fr = [u8; 32];
+
+    const ID: &'static str = "keccak-tornado-zeros";
+
+    fn serialize(value: Self::Fr) -> Value {
+        value.to_vec()
+    }
+
+    fn deserialize(value: Value) -> PmtreeResult<Self::Fr> {
+        value
+            .try_into()
+            .map_err(|_| PmtreeErrorKind::CustomError(String::from("Invalid value length")))
+    }
+
+    fn hash(&self, input: &[Self::Fr]) -> Self::Fr {
+        MyKeccak.hash(input)
+    }
+
+    fn zero_values(&self, depth: usize) -> Vec<Self::Fr> {
+        (0..=depth).map(Self::zero).collect()
+    }
+}
+
+#[test]
+fn custom_zero_values() -> PmtreeResult<()> {
+    let mut mt = MerkleTree::<MemoryDB, ZeroLadderKeccak>::new(2, MemoryDBConfig)?;
+
+    assert_eq!(mt.root(), ZeroLadderKeccak::zero(0));
+    assert_eq!(
+        mt.proof(0)?.get_path_elements(),
+        vec![ZeroLadderKeccak::zero(2), ZeroLadderKeccak::zero(1)]
+    );
+
+    let leaf = hex!("0000000000000000000000000000000000000000000000000000000000000001");
+    mt.update_next(leaf)?;
+
+    let expected_root = MyKeccak.hash(&[
+        MyKeccak.hash(&[leaf, ZeroLadderKeccak::zero(2)]),
+        ZeroLadderKeccak::zero(1),
+    ]);
+    assert_eq!(mt.root(), expected_root);
+    assert!(mt.verify(&leaf, &mt.proof(0)?));
+
+    Ok(())
+}
+
+// Keccak with a consistent zero ladder built from a leaf zero value other than `default_leaf`
+#[derive(Default)]
+struct LeafZeroKeccak;
+
+impl Hasher for LeafZeroKeccak {
+    type Fr = [u8; 32];
+
+    const ID: &'static str = "keccak-leaf-zero";
+
+    fn serialize(value: Self::Fr) -> Value {
+        value.to_vec()
+    }
+
+    fn deserialize(value: Value) -> PmtreeResult<Self::Fr> {
+        MyKeccak::deserialize(value)
+    }
+
+    fn hash(&self, input: &[Self::Fr]) -> Self::Fr {
+        MyKeccak.hash(input)
+    }
+
+    fn zero_values(&self, depth: usize) -> Vec<Self::Fr> {
+        let mut zeros = vec![[0xff; 32]; depth + 1];
+        for level in (0..depth).rev() {
+            zeros[level] = self.hash(&[zeros[level + 1], zeros[level + 1]]);
+        }
+
+        zeros
+    }
+}
+
+#[test]
+fn delete_custom_leaf_zero() -> PmtreeResult<()> {
+    let mut mt = MerkleTree::<MemoryDB, LeafZeroKeccak>::new(3, MemoryDBConfig)?;
+    assert_ne!(
+        LeafZeroKeccak.zero_values(3)[3],
+        LeafZeroKeccak::default_leaf()
+    );
+
+    let empty_root = mt.root();
+    let metadata_len = mt.db().0.len();
+
+    let leaf = hex!("0000000000000000000000000000000000000000000000000000000000000001");
+    mt.update_next(leaf)?;
+    mt.delete(0)?;
+
+    // The leaf zero value is restored and pruned, as in the empty tree
+    assert_eq!(mt.root(), empty_root);
+    assert_eq!(mt.occupied_count(), 0);
+    assert_eq!(mt.get(0)?, [0xff; 32]);
+    assert_eq!(mt.db().0.len(), metadata_len);
+
+    Ok(())
+}
+
+// Keyed hasher: the Keccak state is pre-seeded with a key at runtime
+struct KeyedKeccak(Keccak);
+
+impl KeyedKeccak {
+    fn new(key: &[u8]) -> Self {
+        let mut state = Keccak::v256();
+        state.update(key);
+        KeyedKeccak(state)
+    }
+}
+
+impl Hasher for KeyedKeccak {
+    type Fr = [u8; 32];
+
+    const ID: &'static str = "keccak-keyed";
+
+    fn serialize(value: Self::Fr) -> Value {
+        value.to_vec()
+    }
+
+    fn deserialize(value: Value) -> PmtreeResult<Self::Fr> {
+        value
+            .try_into()
+            .map_err(|_| PmtreeErrorKind::CustomError(String::from("Invalid value length")))
+    }
+
+    fn hash(&self, input: &[Self::Fr]) -> Self::Fr {
+        let mut output = [0; 32];
+        let mut hasher = self.0.clone();
+        for element in input {
+            hasher.update(element);
+        }
+        hasher.finalize(&mut output);
+        output
+    }
+}
+
+#[test]
+fn stateful_hasher() -> PmtreeResult<()> {
+    let mut mt_a = MerkleTree::<MemoryDB, KeyedKeccak>::with_hasher(
+        2,
+        MemoryDBConfig,
+        KeyedKeccak::new(b"key a"),
+    )?;
+    let mut mt_b = MerkleTree::<MemoryDB, KeyedKeccak>::with_hasher(
+        2,
+        MemoryDBConfig,
+        KeyedKeccak::new(b"key b"),
+    )?;
+
+    let leaves = [
+        hex!("0000000000000000000000000000000000000000000000000000000000000001"),
+        hex!("0000000000000000000000000000000000000000000000000000000000000002"),
+        hex!("0000000000000000000000000000000000000000000000000000000000000003"),
+    ];
+
+    mt_a.batch_insert(None, &leaves)?;
+    for &leaf in leaves.iter() {
+        mt_b.update_next(leaf)?;
+    }
+
+    assert_ne!(mt_a.root(), mt_b.root());
+
+    let proof = mt_a.proof(1)?;
+    assert!(mt_a.verify(&leaves[1], &proof));
+    assert!(!mt_b.verify(&leaves[1], &proof));
+    assert_eq!(
+        proof.compute_root_with_hasher(mt_a.hasher(), &leaves[1]),
+        mt_a.root()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn corrupted_node() -> PmtreeResult<()> {
+    let mut mt = MerkleTree::<MemoryDB, MyKeccak>::new(2, MemoryDBConfig)?;
+
+    let leaf = hex!("00000000000000000000000000000000000000000000000000000000000000ff");
+    mt.update_next(leaf)?;
+
+    // Truncate the stored leaf
+    for value in mt.db_mut().0.values_mut() {
+        if value[..] == leaf[..] {
+            value.truncate(16);
+        }
+    }
+
+    assert!(matches!(
+        mt.get(0),
+        Err(PmtreeErrorKind::TreeError(TreeErrorKind::CorruptedNode(_)))
+    ));
+    assert!(matches!(
+        mt.proof(1),
+        Err(PmtreeErrorKind::TreeError(TreeErrorKind::CorruptedNode(_)))
+    ));
+    assert!(mt.get(2).is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn integrity_check_and_rebuild() -> PmtreeResult<()> {
+    let mut mt = MerkleTree::<MemoryDB, MyKeccak>::new(3, MemoryDBConfig)?;
+
+    let leaves = [
+        hex!("0000000000000000000000000000000000000000000000000000000000000001"),
+        hex!("0000000000000000000000000000000000000000000000000000000000000002"),
+        hex!("0000000000000000000000000000000000000000000000000000000000000003"),
+    ];
+
+    mt.set_range(0, leaves)?;
+    let root = mt.root();
+
+    assert!(mt.verify_integrity()?.is_ok());
+
+    // Corrupt the parent of the first two leaves and the stored root
+    let parent = MyKeccak.hash(&[leaves[0], leaves[1]]);
+    for value in mt.db_mut().0.values_mut() {
+        if value[..] == parent[..] || value[..] == root[..] {
+            *value = vec![0xff; 32];
+        }
+    }
+
+    let report = mt.verify_integrity()?;
+    assert!(!report.is_ok());
+    assert!(report.root_mismatch);
+    assert_eq!(report.mismatched_nodes.len(), 3);
+    assert!(report.corrupted_nodes.is_empty());
+    assert!(!report.next_index_mismatch);
+
+    mt.rebuild_internal_nodes()?;
+
+    assert!(mt.verify_integrity()?.is_ok());
+    assert_eq!(mt.root(), root);
+    for (i, leaf) in leaves.iter().enumerate() {
+        assert!(mt.verify(leaf, &mt.proof(i)?));
+    }
+
+    // Garbage right of the set leaves and a leaf beyond next_index, as left by an interrupted update
+    let node_key = |level: u8, index: u8| {
+        let mut key = [0; 16];
+        key[8] = level;
+        key[15] = index;
+        key
+    };
+    mt.db_mut().0.insert(node_key(2, 3), vec![0xee; 32]);
+    mt.db_mut().0.insert(node_key(3, 5), leaves[0].to_vec());
+
+    let report = mt.verify_integrity()?;
+    assert_eq!(report.mismatched_nodes, vec![Key::new(2, 3)]);
+    assert_eq!(report.stray_leaves, vec![5]);
+    assert!(report.next_index_mismatch);
+    assert!(!report.root_mismatch);
+
+    mt.rebuild_internal_nodes()?;
+
+    assert!(mt.verify_integrity()?.is_ok());
+    assert_eq!(mt.root(), root);
+    assert_eq!(mt.get(5)?, [0; 32]);
+    assert_eq!(mt.occupied_count(), 3);
+
+    // Later updates of the region don't pick up the discarded nodes
+    let mut expected = MerkleTree::<MemoryDB, MyKeccak>::new(3, MemoryDBConfig)?;
+    expected.set_range(0, leaves)?;
+    expected.set(6, leaves[2])?;
+    mt.set(6, leaves[2])?;
+    assert_eq!(mt.root(), expected.root());
+
+    Ok(())
+}
+
+#[test]
+fn batch_insertions_thread_pool() -> PmtreeResult<()> {
+    let leaves: Vec<[u8; 32]> = (0..300u32)
+        .map(|i| {
+            let mut leaf = [0; 32];
+            leaf[28..].copy_from_slice(&(i + 1).to_be_bytes());
+            leaf
+        })
+        .collect();
+
+    let mut expected = MerkleTree::<MemoryDB, MyKeccak>::new(10, MemoryDBConfig)?;
+    for &leaf in leaves.iter() {
+        expected.update_next(leaf)?;
+    }
+
+    let mut sequential = MerkleTree::<MemoryDB, MyKeccak>::new(10, MemoryDBConfig)?;
+    sequential.set_parallel_threshold(usize::MAX);
+    sequential.batch_insert(Some(5), &leaves[5..])?;
+    sequential.batch_insert(Some(0), &leaves[..5])?;
+
+    let mut dedicated = MerkleTree::<MemoryDB, MyKeccak>::new(10, MemoryDBConfig)?;
+    dedicated.set_num_threads(2)?;
+    dedicated.set_parallel_threshold(0);
+    dedicated.batch_insert(None, &leaves)?;
+
+    let mut shared = MerkleTree::<MemoryDB, MyKeccak>::new(10, MemoryDBConfig)?;
+    shared.set_thread_pool(std::sync::Arc::new(
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap(),
+    ));
+    shared.batch_insert(None, &leaves)?;
+
+    assert_eq!(sequential.root(), expected.root());
+    assert_eq!(dedicated.root(), expected.root());
+    assert_eq!(shared.root(), expected.root());
+
+    Ok(())
+}
+
+#[test]
+fn batch_insertions_match_sequential() -> PmtreeResult<()> {
+    let leaves = leaves(64);
+
+    // Unaligned starts, runs crossing subtree boundaries and runs ending at capacity
+    let runs = [
+        (0, 32),
+        (1, 31),
+        (3, 5),
+        (5, 1),
+        (7, 10),
+        (13, 19),
+        (15, 2),
+        (29, 3),
+        (31, 1),
+    ];
+
+    for (start, len) in runs {
+        let mut batch = MerkleTree::<MemoryDB, MyKeccak>::new(5, MemoryDBConfig)?;
+        let mut sequential = MerkleTree::<MemoryDB, MyKeccak>::new(5, MemoryDBConfig)?;
+        batch.set_parallel_threshold(0);
+
+        // Some of the edges are already set
+        for i in (0..32).step_by(3) {
+            batch.set(i, leaves[32 + i])?;
+            sequential.set(i, leaves[32 + i])?;
+        }
+
+        batch.batch_insert(Some(start), &leaves[..len])?;
+        for (i, &leaf) in leaves[..len].iter().enumerate() {
+            sequential.set(start + i, leaf)?;
+        }
+
+        assert_eq!(
+            batch.root(),
+            sequential.root(),
+            "run {start}..{}",
+            start + len
+        );
+        assert_eq!(batch.leaves_set(), sequential.leaves_set());
+        for level in 0..=5 {
+            for index in 0..1 << level {
+                assert_eq!(batch.node(level, index)?, sequential.node(level, index)?);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn node_cache() -> PmtreeResult<()> {
+    let mut mt = MerkleTree::<MemoryDB, MyKeccak>::new(4, MemoryDBConfig)?;
+    let mut cached = MerkleTree::<MemoryDB, MyKeccak>::new(4, MemoryDBConfig)?;
+    cached.set_node_cache(2, 8);
+
+    assert!(mt.cache_stats().is_none());
+
+    let leaves = leaves(10);
+
+    for &leaf in leaves[..6].iter() {
+        mt.update_next(leaf)?;
+        cached.update_next(leaf)?;
+    }
+    mt.batch_insert(None, &leaves[6..])?;
+    cached.batch_insert(None, &leaves[6..])?;
+    mt.delete(3)?;
+    cached.delete(3)?;
+
+    assert_eq!(cached.root(), mt.root());
+
+    for i in 0..leaves.len() {
+        assert_eq!(cached.get(i)?, mt.get(i)?);
+        assert_eq!(
+            cached.proof(i)?.get_path_elements(),
+            mt.proof(i)?.get_path_elements()
+        );
+    }
+
+    let stats = cached.cache_stats().unwrap();
+    assert!(stats.hits > 0);
+    assert!(stats.hit_rate() > 0.0);
+    assert_eq!(stats.pinned, 3);
+    assert!(stats.cached <= 8);
+
+    Ok(())
+}
+
+// Memory db counting the read round trips
+struct CountingDB {
+    db: HashMap<DBKey, Value>,
+    gets: std::cell::Cell<usize>,
+    batch_gets: std::cell::Cell<usize>,
+}
+
+impl Database for CountingDB {
+    type Config = MemoryDBConfig;
+
+    fn new(_db_config: MemoryDBConfig) -> PmtreeResult<Self> {
+        Ok(CountingDB {
+            db: HashMap::new(),
+            gets: Default::default(),
+            batch_gets: Default::default(),
+        })
+    }
+
+    fn load(_db_config: MemoryDBConfig) -> PmtreeResult<Self> {
+        Err(PmtreeErrorKind::DatabaseError(
+            DatabaseErrorKind::CannotLoadDatabase,
+        ))
+    }
+
+    fn get(&self, key: DBKey) -> PmtreeResult<Option<Value>> {
+        self.gets.set(self.gets.get() + 1);
+        Ok(self.db.get(&key).cloned())
+    }
+
+    fn get_batch(&self, keys: &[DBKey]) -> PmtreeResult<Vec<Option<Value>>> {
+        self.batch_gets.set(self.batch_gets.get() + 1);
+        Ok(keys.iter().map(|key| self.db.get(key).cloned()).collect())
+    }
+
+    fn put(&mut self, key: DBKey, value: Value) -> PmtreeResult<()> {
+        self.db.insert(key, value);
+
+        Ok(())
+    }
+
+    fn put_batch(&mut self, subtree: HashMap<DBKey, Value>) -> PmtreeResult<()> {
+        self.db.extend(subtree);
+
+        Ok(())
+    }
+
+    fn delete(&mut self, key: DBKey) -> PmtreeResult<()> {
+        self.db.remove(&key);
+
+        Ok(())
+    }
+
+    fn close(&mut self) -> PmtreeResult<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn batched_reads() -> PmtreeResult<()> {
+    let mut mt = MerkleTree::<CountingDB, MyKeccak>::new(10, MemoryDBConfig)?;
+    mt.db().gets.set(0);
+    mt.db().batch_gets.set(0);
+
+    let leaves = leaves(5);
+
+    mt.batch_insert(Some(3), &leaves)?;
+    mt.update_next(leaves[0])?;
+    let proof = mt.proof(4)?;
+
+    assert!(mt.verify(&leaves[1], &proof));
+    assert_eq!(mt.db().gets.get(), 0);
+    assert_eq!(mt.db().batch_gets.get(), 3);
+
+    Ok(())
+}
+
+// Memory db whose batch writes fail on demand
+struct FailingDB {
+    db: MemoryDB,
+    fail: bool,
+}
+
+impl Database for FailingDB {
+    type Config = MemoryDBConfig;
+
+    fn new(db_config: MemoryDBConfig) -> PmtreeResult<Self> {
+        Ok(FailingDB {
+            db: MemoryDB::new(db_config)?,
+            fail: false,
+        })
+    }
+
+    fn load(db_config: MemoryDBConfig) -> PmtreeResult<Self> {
+        MemoryDB::load(db_config).map(|db| FailingDB { db, fail: false })
+    }
+
+    fn get(&self, key: DBKey) -> PmtreeResult<Option<Value>> {
+        self.db.get(key)
+    }
+
+    fn put(&mut self, key: DBKey, value: Value) -> PmtreeResult<()> {
+        self.db.put(key, value)
+    }
+
+    fn put_batch(&mut self, subtree: HashMap<DBKey, Value>) -> PmtreeResult<()> {
+        if self.fail {
+            return Err(PmtreeErrorKind::DatabaseError(
+                DatabaseErrorKind::CustomError(String::from("Write failed")),
+            ));
+        }
+
+        self.db.put_batch(subtree)
+    }
+
+    fn delete(&mut self, key: DBKey) -> PmtreeResult<()> {
+        self.db.delete(key)
+    }
+
+    fn iter_range(&self, from: DBKey, to: DBKey) -> PmtreeResult<DBIterator<'_>> {
+        self.db.iter_range(from, to)
+    }
+
+    fn close(&mut self) -> PmtreeResult<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn failed_writes() -> PmtreeResult<()> {
+    let mut mt = MerkleTree::<FailingDB, MyKeccak>::new(3, MemoryDBConfig)?;
+    mt.set_node_cache(1, 16);
+    mt.enable_leaf_index()?;
+    mt.enable_free_list()?;
+
+    let leaves = leaves(4);
+    mt.batch_insert(None, &leaves[..2])?;
+    mt.delete(0)?;
+    let root = mt.root();
+
+    // Failed updates leave the tree as it was stored
+    mt.db_mut().fail = true;
+    assert!(mt.batch_insert(None, &leaves[2..]).is_err());
+    assert!(mt.set(1, leaves[3]).is_err());
+    assert!(mt.insert(leaves[3]).is_err());
+    assert!(mt.delete(1).is_err());
+
+    assert_eq!(mt.root(), root);
+    assert_eq!(mt.leaves_set(), 2);
+    assert_eq!(mt.occupied_count(), 1);
+    assert_eq!(mt.free_slots(), vec![0..1]);
+    assert_eq!(mt.get(1)?, leaves[1]);
+    assert_eq!(mt.get(2)?, MyKeccak::default_leaf());
+    assert_eq!(mt.index_of(&leaves[3])?, None);
+    assert!(mt.verify_integrity()?.is_ok());
+
+    // The tree keeps working once the db does
+    mt.db_mut().fail = false;
+    assert_eq!(mt.insert(leaves[3])?, 0);
+    mt.batch_insert(None, &leaves[2..3])?;
+    assert_eq!(mt.leaves_set(), 3);
+    assert_eq!(mt.index_of(&leaves[3])?, Some(0));
+    assert!(mt.verify_integrity()?.is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn prune_default_nodes() -> PmtreeResult<()> {
+    let mut mt = MerkleTree::<MemoryDB, MyKeccak>::new(3, MemoryDBConfig)?;
+
+    // Only the metadata and the registry of trees are stored for an empty tree
+    let metadata_len = mt.db().0.len();
+    assert_eq!(metadata_len, 5);
+
+    let leaves = [
+        hex!("0000000000000000000000000000000000000000000000000000000000000001"),
+        hex!("0000000000000000000000000000000000000000000000000000000000000002"),
+        hex!("0000000000000000000000000000000000000000000000000000000000000003"),
+    ];
+
+    mt.batch_insert(None, &leaves)?;
+    mt.update_next(leaves[0])?;
+    assert!(mt.db().0.len() > metadata_len);
+
+    for i in 0..4 {
+        mt.delete(i)?;
+    }
+
+    assert_eq!(mt.db().0.len(), metadata_len);
+    assert_eq!(
+        mt.root(),
+        MerkleTree::<MemoryDB, MyKeccak>::new(3, MemoryDBConfig)?.root()
+    );
+
+    mt.set_range(1, [MyKeccak::default_leaf(), leaves[2]])?;
+    mt.set(2, MyKeccak::default_leaf())?;
+    mt.delete(1)?;
+    assert_eq!(mt.db().0.len(), metadata_len);
+
+    Ok(())
+}
+
+#[test]
+fn iterate_leaves() -> PmtreeResult<()> {
+    let mut mt = MerkleTree::<MemoryDB, MyKeccak>::new(8, MemoryDBConfig)?;
+
+    let leaves = leaves(6);
+
+    mt.set_range(3, leaves[..3].iter().copied())?;
+    mt.set(200, leaves[3])?;
+    mt.set(17, leaves[4])?;
+    mt.update_next(leaves[5])?;
+    mt.delete(4)?;
+
+    let set_leaves = mt.iter_set_leaves()?.collect::<PmtreeResult<Vec<_>>>()?;
+    assert_eq!(
+        set_leaves,
+        vec![
+            (3, leaves[0]),
+            (5, leaves[2]),
+            (17, leaves[4]),
+            (200, leaves[3]),
+            (201, leaves[5])
+        ]
+    );
+
+    let range = mt.leaves(4..18)?.collect::<PmtreeResult<Vec<_>>>()?;
+    assert_eq!(range, vec![(5, leaves[2]), (17, leaves[4])]);
+
+    assert!(mt.leaves(0..257).is_err());
+
+    // The last leaf of the deepest tree is in range
+    let mut deepest = MerkleTree::<MemoryDB, MyKeccak>::new(tree::MAX_DEPTH, MemoryDBConfig)?;
+    let last = deepest.capacity() - 1;
+    deepest.set(last, leaves[0])?;
+    assert_eq!(
+        deepest
+            .leaves(0..deepest.capacity())?
+            .collect::<PmtreeResult<Vec<_>>>()?,
+        vec![(last, leaves[0])]
+    );
+    assert_eq!(deepest.leaves(last + 1..deepest.capacity())?.count(), 0);
+
+    let counting = MerkleTree::<CountingDB, MyKeccak>::new(2, MemoryDBConfig)?;
+    assert!(matches!(
+        counting.iter_set_leaves().err(),
+        Some(PmtreeErrorKind::DatabaseError(
+            DatabaseErrorKind::UnsupportedOperation(_)
+        ))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn borrowed_and_shared_db() -> PmtreeResult<()> {
+    let leaves = [
+        hex!("0000000000000000000000000000000000000000000000000000000000000001"),
+        hex!("0000000000000000000000000000000000000000000000000000000000000002"),
+    ];
+
+    // Borrowed db outlives the tree
+    let mut db = MemoryDB::new(MemoryDBConfig)?;
+    let root = {
+        let mut mt = MerkleTree::<&mut MemoryDB, MyKeccak>::new_with_db(&mut db, 2)?;
+        mt.set_range(0, leaves)?;
+        mt.root()
+    };
+
+    let mt = MerkleTree::<&mut MemoryDB, MyKeccak>::load_with_db(&mut db)?;
+    assert_eq!(mt.root(), root);
+    assert!(MerkleTree::<&mut MemoryDB, MyKeccak>::new(2, MemoryDBConfig).is_err());
+
+    // Shared db is used by several trees at once
+    let shared = std::sync::Arc::new(std::sync::Mutex::new(MemoryDB::new(MemoryDBConfig)?));
+    let mut first = MerkleTree::<_, MyKeccak>::new_in(shared.clone(), 1, 2)?;
+    let mut second = MerkleTree::<_, MyKeccak>::new_in(shared.clone(), 2, 2)?;
+
+    first.set_range(0, leaves)?;
+    second.update_next(leaves[1])?;
+
+    assert_eq!(first.root(), root);
+    assert_eq!(second.get(0)?, leaves[1]);
+    assert_eq!(list_trees(&shared)?, vec![1, 2]);
+
+    // Namespaced trees can hash through a hasher instance
+    let mut keyed = MerkleTree::<_, KeyedKeccak>::new_in_with_hasher(
+        shared.clone(),
+        3,
+        2,
+        KeyedKeccak::new(b"key"),
+    )?;
+    keyed.set_range(0, leaves)?;
+    let keyed_root = keyed.root();
+    assert_ne!(keyed_root, root);
+    drop(keyed);
+    let keyed = MerkleTree::<_, KeyedKeccak>::load_in_with_hasher(
+        shared.clone(),
+        3,
+        KeyedKeccak::new(b"key"),
+    )?;
+    assert_eq!(keyed.root(), keyed_root);
+    drop(keyed);
+
+    // The registry of trees can't be dropped
+    assert!(matches!(
+        drop_tree(&mut shared.clone(), u64::MAX),
+        Err(PmtreeErrorKind::TreeError(TreeErrorKind::ReservedTreeId))
+    ));
+    assert_eq!(list_trees(&shared)?, vec![1, 2, 3]);
+
+    let db = first.into_inner();
+    drop(second);
+    let loaded = MerkleTree::<_, MyKeccak>::load_in(db, 1)?;
+    assert_eq!(loaded.root(), root);
+
+    Ok(())
+}
+
+#[test]
+fn shared_tree_readers() -> PmtreeResult<()> {
+    let shared = SharedMerkleTree::new(MerkleTree::<MemoryDB, MyKeccak>::new(6, MemoryDBConfig)?);
+
+    let leaves = leaves(40);
+
+    std::thread::scope(|scope| {
+        let writer = shared.clone();
+        let leaves = &leaves;
+        scope.spawn(move || {
+            for chunk in leaves.chunks(4) {
+                let mut tree = writer.write().unwrap();
+                for &leaf in chunk {
+                    tree.update_next(leaf).unwrap();
+                }
+            }
+        });
+
+        for _ in 0..4 {
+            let reader = shared.clone();
+            scope.spawn(move || {
+                for _ in 0..20 {
+                    let snapshot = reader.snapshot();
+                    let set = snapshot.leaves_set();
+
+                    // The writer commits whole chunks
+                    assert_eq!(set % 4, 0);
+                    for (i, leaf) in leaves.iter().enumerate().take(set) {
+                        let proof = snapshot.proof(i).unwrap();
+                        assert!(snapshot.verify(leaf, &proof));
+                        assert_eq!(proof.compute_root_from(leaf), snapshot.root());
+                    }
+                }
+            });
+        }
+    });
+
+    let expected_root = shared.snapshot().root();
+    assert_eq!(shared.root(), expected_root);
+
+    let Ok(tree) = shared.into_inner() else {
+        panic!("the tree is still shared");
+    };
+    assert_eq!(tree.leaves_set(), leaves.len());
+
+    Ok(())
+}
+
+#[test]
+fn shared_tree_versions() -> PmtreeResult<()> {
+    let shared = SharedMerkleTree::new(MerkleTree::<MemoryDB, MyKeccak>::new(3, MemoryDBConfig)?);
+    let leaves = leaves(4);
+
+    let empty = shared.snapshot();
+    let empty_root = empty.root();
+
+    let mut writer = shared.write()?;
+    writer.set_range(0, leaves[..2].to_vec())?;
+
+    // Snapshots are taken while the writer is held and see the last published version
+    let before = shared.snapshot();
+    assert_eq!(before.root(), empty_root);
+    assert_eq!(before.get(1)?, [0; 32]);
+
+    writer.commit();
+    let first = shared.snapshot();
+    assert_eq!(first.root(), writer.root());
+    assert_eq!(first.leaves_set(), 2);
+
+    // Old versions stay intact while the writer overwrites and deletes their nodes
+    writer.set(1, leaves[2])?;
+    writer.delete(0)?;
+    writer.update_next(leaves[3])?;
+    drop(writer);
+
+    assert_eq!(shared.root(), shared.snapshot().root());
+    assert_ne!(shared.root(), first.root());
+
+    for (snapshot, expected) in [(&empty, [[0; 32]; 2]), (&first, [leaves[0], leaves[1]])] {
+        for (i, leaf) in expected.iter().enumerate() {
+            assert_eq!(snapshot.get(i)?, *leaf);
+            let proof = snapshot.proof(i)?;
+            assert!(snapshot.verify(leaf, &proof));
+            assert_eq!(proof.compute_root_from(leaf), snapshot.root());
+        }
+    }
+
+    let last = shared.snapshot();
+    assert_eq!(last.get(1)?, leaves[2]);
+    assert_eq!(last.leaves_set(), 3);
+
+    // The tree is returned once the snapshots are gone
+    let Err(shared) = shared.into_inner() else {
+        panic!("snapshots are alive");
+    };
+    drop((empty, before, first, last));
+    assert!(shared.into_inner().is_ok_and(|tree| tree.leaves_set() == 3));
+
+    Ok(())
+}
+
+#[test]
+fn leaf_index() -> PmtreeResult<()> {
+    let mut mt = MerkleTree::<MemoryDB, MyKeccak>::new(4, MemoryDBConfig)?;
+
+    let leaves = leaves(6);
+
+    mt.update_next(leaves[0])?;
+    assert!(mt.index_of(&leaves[0]).is_err());
+
+    // Already set leaves are indexed when the index is enabled
+    mt.enable_leaf_index()?;
+    assert_eq!(mt.index_of(&leaves[0])?, Some(0));
+
+    mt.batch_insert(None, &leaves[1..5])?;
+    mt.set(9, leaves[5])?;
+    for (i, leaf) in leaves[..5].iter().enumerate() {
+        assert_eq!(mt.index_of(leaf)?, Some(i));
+    }
+    assert_eq!(mt.index_of(&leaves[5])?, Some(9));
+
+    let proof = mt.proof_for_leaf(&leaves[5])?.unwrap();
+    assert_eq!(proof.leaf_index(), 9);
+    assert!(mt.verify(&leaves[5], &proof));
+
+    // Overwritten and deleted leaves leave the index
+    mt.set(2, leaves[0])?;
+    mt.delete(3)?;
+    assert_eq!(mt.index_of(&leaves[2])?, None);
+    assert_eq!(mt.index_of(&leaves[3])?, None);
+    assert_eq!(mt.index_of(&leaves[0])?, Some(0));
+    assert!(mt.proof_for_leaf(&leaves[3])?.is_none());
+    assert!(mt.proof_for_leaf(&[0; 32])?.is_none());
+
+    // The lowest index is reported for duplicated leaves
+    mt.delete(0)?;
+    assert_eq!(mt.index_of(&leaves[0])?, Some(2));
+
+    // The index is persisted
+    let mt = MerkleTree::<MemoryDB, MyKeccak>::load_with_db(mt.into_inner())?;
+    assert!(mt.has_leaf_index());
+    assert_eq!(mt.index_of(&leaves[4])?, Some(4));
+
+    Ok(())
+}
+
+#[test]
+fn free_slots_reuse() -> PmtreeResult<()> {
+    let mut mt = MerkleTree::<MemoryDB, MyKeccak>::new(2, MemoryDBConfig)?;
+
+    let leaves = leaves(6);
+
+    mt.batch_insert(None, &leaves[..3])?;
+    mt.delete(1)?;
+
+    // Deleted leaves are collected when the free list is enabled
+    mt.enable_free_list()?;
+    assert_eq!(mt.free_slots(), vec![1..2]);
+    assert_eq!(mt.occupied_count(), 2);
+
+    mt.set(3, leaves[3])?;
+    mt.delete(0)?;
+    assert_eq!(mt.free_slots(), vec![0..2]);
+    assert_eq!(mt.occupied_count(), 2);
+    assert_eq!(mt.leaves_set(), 4);
+
+    // The lowest freed index is reused first
+    assert_eq!(mt.insert(leaves[4])?, 0);
+    assert_eq!(mt.insert(leaves[5])?, 1);
+    assert!(mt.free_slots().is_empty());
+    assert_eq!(mt.occupied_count(), 4);
+    assert!(mt.insert(leaves[0]).is_err());
+
+    let mut expected = MerkleTree::<MemoryDB, MyKeccak>::new(2, MemoryDBConfig)?;
+    expected.batch_insert(None, &[leaves[4], leaves[5], leaves[2], leaves[3]])?;
+    assert_eq!(mt.root(), expected.root());
+
+    // The free list is persisted
+    mt.delete(2)?;
+    let mt = MerkleTree::<MemoryDB, MyKeccak>::load_with_db(mt.into_inner())?;
+    assert_eq!(mt.free_slots(), vec![2..3]);
+
+    // Without the free list, leaves are appended
+    let mut mt = MerkleTree::<MemoryDB, MyKeccak>::new(2, MemoryDBConfig)?;
+    mt.update_next(leaves[0])?;
+    mt.delete(0)?;
+    assert_eq!(mt.insert(leaves[1])?, 1);
+    assert!(mt.free_slots().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn free_slots_sparse() -> PmtreeResult<()> {
+    let mut mt = MerkleTree::<MemoryDB, MyKeccak>::new(30, MemoryDBConfig)?;
+    mt.enable_free_list()?;
+
+    let leaves = leaves(3);
+
+    // Skipped indexes are kept as one range
+    mt.set(5_000_000, leaves[0])?;
+    assert_eq!(mt.free_slots(), vec![0..5_000_000]);
+
+    let mut free_list_key = [0; 16];
+    free_list_key[8] = 0xff;
+    free_list_key[15] = 4;
+    assert_eq!(mt.db().0[&free_list_key].len(), 16);
+
+    mt.set(2, leaves[1])?;
+    assert_eq!(mt.free_slots(), vec![0..2, 3..5_000_000]);
+    assert_eq!(mt.insert(leaves[2])?, 0);
+    assert_eq!(mt.free_slots(), vec![1..2, 3..5_000_000]);
+
+    // Unchanged free list isn't written
+    mt.db_mut().0.remove(&free_list_key);
+    mt.update_next(leaves[0])?;
+    mt.set(2, leaves[2])?;
+    assert!(!mt.db().0.contains_key(&free_list_key));
+
+    // Freed slots merge with the neighbouring ranges
+    mt.delete(2)?;
+    mt.delete(0)?;
+    assert_eq!(mt.free_slots(), vec![0..5_000_000]);
+
+    let mt = MerkleTree::<MemoryDB, MyKeccak>::load_with_db(mt.into_inner())?;
+    assert_eq!(mt.free_slots(), vec![0..5_000_000]);
+    assert_eq!(mt.leaves_set(), 5_000_002);
+
+    Ok(())
+}
+
+#[test]
+fn occupied_count() -> PmtreeResult<()> {
+    let mut mt = MerkleTree::<MemoryDB, MyKeccak>::new(3, MemoryDBConfig)?;
+
+    let leaves = leaves(4);
+
+    // Gaps and default leaves aren't counted
+    mt.set_range(2, [leaves[0], [0; 32], leaves[1]])?;
+    assert_eq!(mt.leaves_set(), 5);
+    assert_eq!(mt.occupied_count(), 2);
+
+    mt.update_next(leaves[2])?;
+    mt.set(2, leaves[3])?;
+    assert_eq!(mt.occupied_count(), 3);
+
+    // Deleting a default leaf changes nothing
+    mt.delete(2)?;
+    mt.delete(2)?;
+    assert_eq!(mt.occupied_count(), 2);
+
+    mt.batch_insert(Some(4), &[leaves[0], [0; 32]])?;
+    assert_eq!(mt.occupied_count(), 1);
+    assert_eq!(mt.leaves_set(), 6);
+
+    // The count is persisted
+    let mt = MerkleTree::<MemoryDB, MyKeccak>::load_with_db(mt.into_inner())?;
+    assert_eq!(mt.occupied_count(), 1);
+
+    // Inconsistent stored count doesn't underflow
+    let mut db = mt.into_inner();
+    let mut occupied_key = [0; 16];
+    occupied_key[8] = 0xff;
+    occupied_key[15] = 5;
+    db.0.insert(occupied_key, 0usize.to_be_bytes().to_vec());
+    let mut mt = MerkleTree::<MemoryDB, MyKeccak>::load_with_db(db)?;
+    mt.delete(4)?;
+    assert_eq!(mt.occupied_count(), 0);
+
+    // Missing count isn't recounted
+    let mut db = mt.into_inner();
+    db.0.remove(&occupied_key);
+    assert!(matches!(
+        MerkleTree::<MemoryDB, MyKeccak>::load_with_db(db),
+        Err(PmtreeErrorKind::DatabaseError(
+            DatabaseErrorKind::MissingMetadata("occupied_count")
+        ))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn snapshot_export_import() -> PmtreeResult<()> {
+    let leaves = leaves(40);
+
+    // CountingDB has no range scans, so leaves are exported in batches
+    let mut mt = MerkleTree::<CountingDB, MyKeccak>::new(6, MemoryDBConfig)?;
+    mt.batch_insert(None, &leaves[..30])?;
+    mt.set_range(35, leaves[30..].iter().copied())?;
+    mt.delete(3)?;
+    mt.delete(44)?;
+
+    let mut snapshot = Vec::new();
+    mt.export(&mut snapshot)?;
+
+    let header = SnapshotHeader::read(&mut snapshot.as_slice())?;
+    assert_eq!(header.depth, 6);
+    assert_eq!(header.next_index, 45);
+    assert_eq!(header.leaves, 38);
+
+    let imported = MerkleTree::<MemoryDB, MyKeccak>::import(snapshot.as_slice(), MemoryDBConfig)?;
+    assert_eq!(imported.root(), mt.root());
+    assert_eq!(imported.leaves_set(), mt.leaves_set());
+    assert_eq!(imported.occupied_count(), 38);
+    assert_eq!(imported.get(36)?, leaves[31]);
+
+    // Round trip through a db with range scans
+    let mut exported = Vec::new();
+    imported.export(&mut exported)?;
+    assert_eq!(exported, snapshot);
+
+    // Snapshots of other hashers are refused
+    assert!(matches!(
+        MerkleTree::<MemoryDB, PrefixedKeccak>::import(snapshot.as_slice(), MemoryDBConfig),
+        Err(PmtreeErrorKind::SnapshotError(
+            SnapshotErrorKind::HasherMismatch { .. }
+        ))
+    ));
+
+    // Truncated snapshots fail to read
+    assert!(matches!(
+        MerkleTree::<MemoryDB, MyKeccak>::import(&snapshot[..snapshot.len() - 1], MemoryDBConfig),
+        Err(PmtreeErrorKind::SnapshotError(SnapshotErrorKind::Io(_)))
+    ));
+
+    // Tampered leaves don't match the root, the failed import leaves nothing behind
+    let mut tampered = snapshot.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    let mut db = MemoryDB::new(MemoryDBConfig)?;
+    assert!(matches!(
+        MerkleTree::<&mut MemoryDB, MyKeccak>::import_with(
+            tampered.as_slice(),
+            &mut db,
+            7,
+            MyKeccak
+        ),
+        Err(PmtreeErrorKind::SnapshotError(
+            SnapshotErrorKind::RootMismatch
+        ))
+    ));
+    assert!(db.0.is_empty());
+
+    // So do malformed leaves
+    let mut bad_index = Vec::new();
+    SnapshotHeader {
+        leaves: 1,
+        ..header.clone()
+    }
+    .write(&mut bad_index)?;
+    bad_index.extend((header.next_index as u64).to_be_bytes());
+    bad_index.extend(32u32.to_be_bytes());
+    bad_index.extend([1; 32]);
+    assert!(matches!(
+        MerkleTree::<&mut MemoryDB, MyKeccak>::import_with(
+            bad_index.as_slice(),
+            &mut db,
+            7,
+            MyKeccak
+        ),
+        Err(PmtreeErrorKind::SnapshotError(
+            SnapshotErrorKind::Malformed("leaf index")
+        ))
+    ));
+    assert!(db.0.is_empty());
+
+    // The import can be retried under the same id
+    let imported = MerkleTree::<&mut MemoryDB, MyKeccak>::import_with(
+        snapshot.as_slice(),
+        &mut db,
+        7,
+        MyKeccak,
+    )?;
+    assert_eq!(imported.root(), mt.root());
+    drop(imported);
+    assert_eq!(list_trees(&db)?, vec![7]);
+
+    // Malformed headers are refused before the tree is created
+    let mut malformed = Vec::new();
+    SnapshotHeader {
+        next_index: 65,
+        ..header.clone()
+    }
+    .write(&mut malformed)?;
+    let mut db = MemoryDB::new(MemoryDBConfig)?;
+    assert!(matches!(
+        MerkleTree::<&mut MemoryDB, MyKeccak>::import_with_db(malformed.as_slice(), &mut db),
+        Err(PmtreeErrorKind::SnapshotError(
+            SnapshotErrorKind::Malformed("next_index")
+        ))
+    ));
+    assert!(db.0.is_empty());
+
+    // Snapshots can be imported in a namespace with a hasher instance
+    let mut keyed = MerkleTree::<MemoryDB, KeyedKeccak>::with_hasher(
+        6,
+        MemoryDBConfig,
+        KeyedKeccak::new(b"key"),
+    )?;
+    keyed.batch_insert(None, &leaves[..5])?;
+    let mut keyed_snapshot = Vec::new();
+    keyed.export(&mut keyed_snapshot)?;
+    let imported = MerkleTree::<MemoryDB, KeyedKeccak>::import_with(
+        keyed_snapshot.as_slice(),
+        MemoryDB::new(MemoryDBConfig)?,
+        7,
+        KeyedKeccak::new(b"key"),
+    )?;
+    assert_eq!(imported.tree_id(), 7);
+    assert_eq!(imported.root(), keyed.root());
+
+    // Inconsistent stored count of non-default leaves is reported as such
+    let mut occupied_key = [0; 16];
+    occupied_key[8] = 0xff;
+    occupied_key[15] = 5;
+    keyed
+        .db_mut()
+        .0
+        .insert(occupied_key, 4usize.to_be_bytes().to_vec());
+    let keyed = MerkleTree::<MemoryDB, KeyedKeccak>::load_in_with_hasher(
+        keyed.into_inner(),
+        0,
+        KeyedKeccak::new(b"key"),
+    )?;
+    assert!(matches!(
+        keyed.export(Vec::new()),
+        Err(PmtreeErrorKind::DatabaseError(
+            DatabaseErrorKind::MalformedMetadata("occupied_count")
+        ))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn proof_encoding() -> PmtreeResult<()> {
+    let leaves = [
+        hex!("0000000000000000000000000000000000000000000000000000000000000001"),
+        hex!("0000000000000000000000000000000000000000000000000000000000000002"),
+        hex!("0000000000000000000000000000000000000000000000000000000000000003"),
+    ];
+
+    let mut mt = MerkleTree::<MemoryDB, MyKeccak>::new(3, MemoryDBConfig)?;
+    mt.batch_insert(None, &leaves)?;
+
+    let proof = mt.proof(2)?;
+    let bytes = proof.to_bytes();
+    assert_eq!(bytes.len(), 8 + 3 * 33);
+
+    let decoded = MerkleProof::<MyKeccak>::from_bytes(&bytes)?;
+    assert_eq!(decoded, proof);
+    assert!(mt.verify(&leaves[2], &decoded));
+    assert!(format!("{decoded:?}").starts_with("MerkleProof"));
+
+    assert!(MerkleProof::<MyKeccak>::from_bytes_with_depth(&bytes, 3).is_ok());
+    assert!(matches!(
+        MerkleProof::<MyKeccak>::from_bytes_with_depth(&bytes, 4),
+        Err(PmtreeErrorKind::TreeError(TreeErrorKind::DepthMismatch {
+            expected: 4,
+            actual: 3
+        }))
+    ));
+
+    // Truncated input and invalid path indexes are refused
+    assert!(matches!(
+        MerkleProof::<MyKeccak>::from_bytes(&bytes[..bytes.len() - 1]),
+        Err(PmtreeErrorKind::TreeError(TreeErrorKind::MalformedProof(_)))
+    ));
+    let mut tampered = bytes.clone();
+    tampered[8] = 2;
+    assert!(matches!(
+        MerkleProof::<MyKeccak>::from_bytes(&tampered),
+        Err(PmtreeErrorKind::TreeError(TreeErrorKind::MalformedProof(_)))
+    ));
+
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_string(&proof).unwrap();
+        let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+        assert_eq!(json, format!("\"0x{hex}\""));
+        assert_eq!(
+            serde_json::from_str::<MerkleProof<MyKeccak>>(&json).unwrap(),
+            proof
+        );
+        assert!(serde_json::from_str::<MerkleProof<MyKeccak>>("\"0x00\"").is_err());
+    }
+
+    Ok(())
+}
+
+// Hasher whose default leaf isn't `Fr::default()`
+struct OnesLeafKeccak;
+
+impl Hasher for OnesLeafKeccak {
+    type Fr = [u8; 32];
+
+    const ID: &'static str = "keccak-ones-leaf";
+
+    fn default_leaf() -> Self::Fr {
+        [0xff; 32]
+    }
+
+    fn serialize(value: Self::Fr) -> Value {
+        MyKeccak::serialize(value)
+    }
+
+    fn deserialize(value: Value) -> PmtreeResult<Self::Fr> {
+        MyKeccak::deserialize(value)
+    }
+
+    fn hash(&self, input: &[Self::Fr]) -> Self::Fr {
+        MyKeccak.hash(input)
+    }
+}
+
+#[test]
+fn circom_input() -> PmtreeResult<()> {
+    let mut mt = MerkleTree::<MemoryDB, MyKeccak>::new(2, MemoryDBConfig)?;
+    mt.set(
+        1,
+        hex!("0000000000000000000000000000000000000000000000000000000000000001"),
+    )?;
+
+    let proof = mt.proof(1)?;
+    let input = proof.to_circom_input(circom::decimal_be);
+    assert_eq!(input.path_elements[0], "0");
+    assert_eq!(
+        input.path_elements[1],
+        "78338746147236970124700731725183845421594913511827187288591969170390706184117"
+    );
+    assert_eq!(input.identity_path_index, vec![1, 0]);
+
+    assert_eq!(
+        proof.to_circom_json(circom::hex),
+        format!(
+            "{{\"pathElements\":[\"0x{}\",\"0x{}\"],\"identityPathIndex\":[1,0]}}",
+            "0".repeat(64),
+            "ad3228b676f7d3cd4284a5443f17f1962b36e491b30a40b2405849e597ba5fb5"
+        )
+    );
+
+    // Circuits of a larger fixed depth get default padding
+    let padded = proof.to_circom_input_padded(4, circom::decimal_le)?;
+    assert_eq!(padded.path_elements.len(), 4);
+    assert_eq!(padded.path_elements[3], "0");
+    assert_eq!(padded.identity_path_index, vec![1, 0, 0, 0]);
+    assert!(proof.to_circom_input_padded(1, circom::hex).is_err());
+
+    // Padding is the default leaf of the hasher
+    let proof = MerkleProof::<OnesLeafKeccak>(vec![([1; 32], 0)]);
+    let padded = proof.to_circom_input_padded(2, circom::hex)?;
+    assert_eq!(padded.path_elements[1], format!("0x{}", "f".repeat(64)));
+
+    Ok(())
+}
+
+#[test]
+fn standalone_verification() -> PmtreeResult<()> {
+    let leaves = [
+        hex!("0000000000000000000000000000000000000000000000000000000000000001"),
+        hex!("0000000000000000000000000000000000000000000000000000000000000002"),
+        hex!("0000000000000000000000000000000000000000000000000000000000000003"),
+    ];
+
+    let mut mt = MerkleTree::<MemoryDB, MyKeccak>::new(3, MemoryDBConfig)?;
+    mt.batch_insert(None, &leaves)?;
+    let root = mt.root();
+
+    // Only the root is needed
+    let proof = mt.proof(2)?;
+    assert!(proof.verify(&leaves[2], &root));
+    assert!(!proof.verify(&leaves[1], &root));
+    assert!(proof.verify_at_index(&leaves[2], 2, &root));
+    assert!(!proof.verify_at_index(&leaves[2], 1, &root));
+    assert!(proof.check_depth(3).is_ok());
+    assert!(matches!(
+        proof.check_depth(2),
+        Err(PmtreeErrorKind::TreeError(TreeErrorKind::DepthMismatch {
+            expected: 2,
+            actual: 3
+        }))
+    ));
+
+    // A proof from the subtree of depth 2 is a suffix of the whole proof, yet it's rejected
+    let mut short = mt.proof(2)?;
+    let top = short.0.pop().unwrap();
+    let subtree_root = short.compute_root_from(&leaves[2]);
+    assert!(short.verify(&leaves[2], &subtree_root));
+    assert_eq!(MyKeccak.hash(&[subtree_root, top.0]), root);
+    assert!(!mt.verify(&subtree_root, &MerkleProof(vec![top])));
+
+    Ok(())
+}
+
+#[test]
+fn subtree_proofs() -> PmtreeResult<()> {
+    let leaves = leaves(6);
+
+    let mut mt = MerkleTree::<MemoryDB, PrefixedKeccak>::new(3, MemoryDBConfig)?;
+    mt.batch_insert(None, &leaves)?;
+
+    // Nodes are addressed by level and index, root is level 0
+    assert_eq!(mt.node(0, 0)?, mt.root());
+    assert_eq!(mt.node(3, 4)?, leaves[4]);
+    assert_eq!(mt.get_elem(Key::new(3, 4))?, leaves[4]);
+    assert_eq!(Key::new(2, 1).level(), 2);
+    assert_eq!(Key::new(2, 1).index(), 1);
+    assert!(mt.node(4, 0).is_err());
+    assert!(mt.node(2, 4).is_err());
+
+    // Keys outside of the tree are refused instead of aliasing stored nodes
+    assert!(matches!(
+        mt.get_elem(Key::new(4, 0)),
+        Err(PmtreeErrorKind::TreeError(TreeErrorKind::IndexOutOfBounds))
+    ));
+    assert!(matches!(
+        mt.get_elem(Key::new(3, (1 << 56) | 4)),
+        Err(PmtreeErrorKind::TreeError(TreeErrorKind::IndexOutOfBounds))
+    ));
+
+    // Subtree of the leaves 4..6
+    let subtree_root = mt.subtree_root(1, 1)?;
+    let expected = PrefixedKeccak.hash_node(
+        1,
+        &[
+            PrefixedKeccak.hash_node(
+                2,
+                &[leaves[4], leaves[5]].map(|l| PrefixedKeccak.hash_leaf(l)),
+            ),
+            mt.subtree_root(2, 3)?,
+        ],
+    );
+    assert_eq!(subtree_root, expected);
+
+    let proof = mt.subtree_proof(1, 1)?;
+    assert_eq!(proof.length(), 1);
+    assert_eq!(proof.leaf_index(), 1);
+    assert_eq!(
+        proof.compute_root_from_subtree(&PrefixedKeccak, &subtree_root),
+        mt.root()
+    );
+    assert!(mt.verify_subtree(1, &subtree_root, &proof));
+    assert!(!mt.verify_subtree(2, &subtree_root, &proof));
+    assert!(!mt.verify_subtree(1, &mt.subtree_root(1, 0)?, &proof));
+
+    // Leaf-level subtrees are the hashed leaves, so the subtree proof is the regular proof
+    assert_eq!(mt.subtree_root(3, 2)?, PrefixedKeccak.hash_leaf(leaves[2]));
+    assert_eq!(mt.subtree_proof(3, 2)?, mt.proof(2)?);
+    assert!(mt.verify_subtree(3, &mt.subtree_root(3, 2)?, &mt.proof(2)?));
+
+    // Proof of the root is empty
+    assert_eq!(mt.subtree_proof(0, 0)?.length(), 0);
+    assert!(mt.verify_subtree(0, &mt.root(), &mt.subtree_proof(0, 0)?));
+
+    Ok(())
+}
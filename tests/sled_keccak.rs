@@ -2,9 +2,12 @@ use hex_literal::hex;
 use pmtree::*;
 use std::collections::HashMap;
 use std::fs;
+use std::thread;
 use tiny_keccak::{Hasher as _, Keccak};
 
-struct MyKeccak(Keccak);
+#[derive(Default)]
+struct MyKeccak;
+#[derive(Clone)]
 struct MySled(sled::Db);
 
 #[derive(Default)]
@@ -63,6 +66,25 @@ impl Database for MySled {
         Ok(())
     }
 
+    fn delete(&mut self, key: DBKey) -> PmtreeResult<()> {
+        self.0.remove(key).unwrap();
+
+        Ok(())
+    }
+
+    fn iter_range(&self, from: DBKey, to: DBKey) -> PmtreeResult<DBIterator<'_>> {
+        Ok(Box::new(self.0.range(from..to).map(|entry| {
+            let (key, value) = entry.unwrap();
+            let key = key.as_ref().try_into().map_err(|_| {
+                PmtreeErrorKind::DatabaseError(DatabaseErrorKind::CustomError(String::from(
+                    "Invalid key length",
+                )))
+            })?;
+
+            Ok((key, value.to_vec()))
+        })))
+    }
+
     fn close(&mut self) -> PmtreeResult<()> {
         self.0.flush().unwrap();
 
@@ -73,6 +95,8 @@ impl Database for MySled {
 impl Hasher for MyKeccak {
     type Fr = [u8; 32];
 
+    const ID: &'static str = "keccak";
+
     fn default_leaf() -> Self::Fr {
         [0; 32]
     }
@@ -81,11 +105,13 @@ impl Hasher for MyKeccak {
         value.to_vec()
     }
 
-    fn deserialize(value: Value) -> Self::Fr {
-        value.to_vec().try_into().unwrap()
+    fn deserialize(value: Value) -> PmtreeResult<Self::Fr> {
+        value
+            .try_into()
+            .map_err(|_| PmtreeErrorKind::CustomError(String::from("Invalid value length")))
     }
 
-    fn hash(input: &[Self::Fr]) -> Self::Fr {
+    fn hash(&self, input: &[Self::Fr]) -> Self::Fr {
         let mut output = [0; 32];
         let mut hasher = Keccak::v256();
         for element in input {
@@ -202,3 +228,223 @@ fn set_range() -> PmtreeResult<()> {
 
     Ok(())
 }
+
+#[test]
+fn load() -> PmtreeResult<()> {
+    let leaves = [
+        hex!("0000000000000000000000000000000000000000000000000000000000000001"),
+        hex!("0000000000000000000000000000000000000000000000000000000000000002"),
+    ];
+
+    let root = {
+        let mut mt = MerkleTree::<MySled, MyKeccak>::new(
+            2,
+            SledConfig {
+                path: String::from("abacabasabac"),
+            },
+        )?;
+        mt.set_range(0, leaves)?;
+        mt.close()?;
+        mt.root()
+    };
+
+    let mismatch = MerkleTree::<MySled, MyKeccak>::load_with_expected_depth(
+        SledConfig {
+            path: String::from("abacabasabac"),
+        },
+        3,
+    );
+    assert!(matches!(
+        mismatch,
+        Err(PmtreeErrorKind::TreeError(TreeErrorKind::DepthMismatch {
+            expected: 3,
+            actual: 2
+        }))
+    ));
+
+    let mt = MerkleTree::<MySled, MyKeccak>::load_with_expected_depth(
+        SledConfig {
+            path: String::from("abacabasabac"),
+        },
+        2,
+    )?;
+    assert_eq!(mt.root(), root);
+    assert_eq!(mt.leaves_set(), 2);
+    assert_eq!(mt.get(1)?, leaves[1]);
+    assert_eq!(
+        mt.iter_set_leaves()?.collect::<PmtreeResult<Vec<_>>>()?,
+        vec![(0, leaves[0]), (1, leaves[1])]
+    );
+    drop(mt);
+
+    fs::remove_dir_all("abacabasabac").expect("Error removing db");
+
+    Ok(())
+}
+
+#[test]
+fn load_missing_metadata() -> PmtreeResult<()> {
+    {
+        let db = sled::open("abacabasabad").unwrap();
+        db.insert(b"unrelated", b"value").unwrap();
+        db.flush().unwrap();
+    }
+
+    let mt = MerkleTree::<MySled, MyKeccak>::load(SledConfig {
+        path: String::from("abacabasabad"),
+    });
+    assert!(matches!(
+        mt,
+        Err(PmtreeErrorKind::DatabaseError(
+            DatabaseErrorKind::MissingMetadata("depth")
+        ))
+    ));
+
+    fs::remove_dir_all("abacabasabad").expect("Error removing db");
+
+    Ok(())
+}
+
+#[test]
+fn load_legacy_layout() -> PmtreeResult<()> {
+    // Depth and next_index records of a 2.x db, stored under 8-byte keys
+    {
+        let db = sled::open("abacabasabag").unwrap();
+        db.insert((u64::MAX - 1).to_be_bytes(), &2usize.to_be_bytes())
+            .unwrap();
+        db.insert(u64::MAX.to_be_bytes(), &0usize.to_be_bytes())
+            .unwrap();
+        db.flush().unwrap();
+    }
+
+    let mt = MerkleTree::<MySled, MyKeccak>::load(SledConfig {
+        path: String::from("abacabasabag"),
+    });
+    assert!(matches!(
+        mt,
+        Err(PmtreeErrorKind::DatabaseError(
+            DatabaseErrorKind::MissingMetadata("depth")
+        ))
+    ));
+
+    fs::remove_dir_all("abacabasabag").expect("Error removing db");
+
+    Ok(())
+}
+
+// Same hash-function as MyKeccak, but registered under another identifier
+#[derive(Default)]
+struct OtherKeccak;
+
+impl Hasher for OtherKeccak {
+    type Fr = [u8; 32];
+
+    const ID: &'static str = "other-keccak";
+
+    fn serialize(value: Self::Fr) -> Value {
+        MyKeccak::serialize(value)
+    }
+
+    fn deserialize(value: Value) -> PmtreeResult<Self::Fr> {
+        MyKeccak::deserialize(value)
+    }
+
+    fn hash(&self, input: &[Self::Fr]) -> Self::Fr {
+        MyKeccak.hash(input)
+    }
+}
+
+#[test]
+fn load_incompatible_hasher() -> PmtreeResult<()> {
+    let mut mt = MerkleTree::<MySled, MyKeccak>::new(
+        2,
+        SledConfig {
+            path: String::from("abacabasabae"),
+        },
+    )?;
+    assert_eq!(mt.header(), TreeHeader::new::<MyKeccak>(2));
+    mt.close()?;
+    drop(mt);
+
+    let other = MerkleTree::<MySled, OtherKeccak>::load(SledConfig {
+        path: String::from("abacabasabae"),
+    });
+    assert!(matches!(
+        other,
+        Err(PmtreeErrorKind::DatabaseError(
+            DatabaseErrorKind::IncompatibleHeader(_)
+        ))
+    ));
+
+    assert!(MerkleTree::<MySled, MyKeccak>::load(SledConfig {
+        path: String::from("abacabasabae"),
+    })
+    .is_ok());
+
+    fs::remove_dir_all("abacabasabae").expect("Error removing db");
+
+    Ok(())
+}
+
+#[test]
+fn shared_db() -> PmtreeResult<()> {
+    let db = MySled::new(SledConfig {
+        path: String::from("abacabasabaf"),
+    })?;
+
+    let leaves = [
+        hex!("0000000000000000000000000000000000000000000000000000000000000001"),
+        hex!("0000000000000000000000000000000000000000000000000000000000000002"),
+    ];
+
+    let mut first = MerkleTree::<MySled, MyKeccak>::new_in(db.clone(), 1, 2)?;
+    let mut second = MerkleTree::<MySled, MyKeccak>::new_in(db.clone(), 2, 3)?;
+
+    assert!(matches!(
+        MerkleTree::<MySled, MyKeccak>::new_in(db.clone(), 1, 2),
+        Err(PmtreeErrorKind::DatabaseError(
+            DatabaseErrorKind::TreeExists(1)
+        ))
+    ));
+
+    first.set_range(0, leaves)?;
+    second.update_next(leaves[1])?;
+
+    assert_eq!(
+        first.root(),
+        hex!("893760ec5b5bee236f29e85aef64f17139c3c1b7ff24ce64eb6315fca0f2485b")
+    );
+    assert_eq!(first.get(1)?, leaves[1]);
+    assert_eq!(second.get(0)?, leaves[1]);
+    assert_eq!(second.tree_id(), 2);
+    assert_eq!(list_trees(&db)?, vec![1, 2]);
+
+    let mut db = db;
+    drop_tree(&mut db, 1)?;
+    assert_eq!(list_trees(&db)?, vec![2]);
+    assert!(MerkleTree::<MySled, MyKeccak>::load_in(db.clone(), 1).is_err());
+
+    let loaded = MerkleTree::<MySled, MyKeccak>::load_in(db.clone(), 2)?;
+    assert_eq!(loaded.root(), second.root());
+    assert_eq!(loaded.depth(), 3);
+
+    // Trees created concurrently through clones of one handle are all registered
+    let handles: Vec<_> = (10..18)
+        .map(|tree_id| {
+            let db = db.clone();
+            thread::spawn(move || MerkleTree::<MySled, MyKeccak>::new_in(db, tree_id, 2).map(drop))
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(
+        list_trees(&db)?,
+        [2].into_iter().chain(10..18).collect::<Vec<_>>()
+    );
+
+    drop((first, second, loaded, db));
+    fs::remove_dir_all("abacabasabaf").expect("Error removing db");
+
+    Ok(())
+}